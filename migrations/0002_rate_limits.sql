@@ -0,0 +1,9 @@
+-- Buckets of API_RATE_LIMIT_BACKEND=postgres, see "Rate limiting" in the README.
+-- Unlogged: losing them in a crash only resets the limits.
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limits (
+    key TEXT PRIMARY KEY,
+    tat_ms BIGINT NOT NULL
+);
+
+-- Purge of the full buckets
+CREATE INDEX IF NOT EXISTS rate_limits_tat ON rate_limits (tat_ms);
@@ -0,0 +1,175 @@
+use deadpool_postgres::{Pool as PgPool, PoolError, TimeoutType};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::{collections::HashMap, time::{Duration, Instant}};
+use crate::metrics;
+use log::{info, warn};
+
+
+// Breaker of every pool, keyed by the address of the pool's manager (shared by all the clones of a pool)
+static BREAKERS: LazyLock<RwLock<HashMap<usize, Arc<Breaker>>>> = LazyLock::new(Default::default);
+
+// What a request is told to wait while the half-open probe is out
+const PROBE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+
+/// Circuit breaker around the checkouts of one pool: opened by consecutive failures it refuses the checkouts
+/// (no load on a Postgres that is down), after the cooldown one checkout probes it and closes or reopens it
+pub struct Breaker {
+    pool: String,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+
+/// State of a breaker as reported in the health checks and the metrics
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BreakerState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+
+/// A checkout let through by the breaker, its outcome is reported with `success` or `failure`
+/// (dropped without one it counts as neither, e.g. a wait for a busy pool)
+pub struct Permit<'a> {
+    breaker: &'a Breaker,
+    probe: bool,
+}
+
+
+
+fn key(pool: &PgPool) -> usize {
+    std::ptr::from_ref(pool.manager()) as usize
+}
+
+
+/// Put a breaker around the checkouts of `pool`, a threshold of 0 leaves it without one
+pub fn register(pool: &PgPool, name: &str, threshold: u32, cooldown: Duration) {
+    if threshold == 0 {
+        return;
+    }
+
+    let breaker = Breaker { pool: name.to_string(), threshold, cooldown, state: Mutex::new(State::Closed { failures: 0 }) };
+    breaker.report(BreakerState::Closed);
+    BREAKERS.write().unwrap().insert(key(pool), Arc::new(breaker));
+}
+
+
+/// Breaker of the pool, if it has one
+pub fn of(pool: &PgPool) -> Option<Arc<Breaker>> {
+    BREAKERS.read().unwrap().get(&key(pool)).cloned()
+}
+
+
+/// True for the checkout errors saying Postgres is unreachable or unresponsive (a busy pool is not an outage)
+pub fn is_outage(e: &PoolError) -> bool {
+    matches!(e, PoolError::Backend(_) | PoolError::Timeout(TimeoutType::Create | TimeoutType::Recycle))
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Breaker {
+    /// Let a checkout through, or the time left before the next probe while the circuit is open
+    pub fn acquire(&self) -> Result<Permit<'_>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(Permit { breaker: self, probe: false }),
+            State::Open { until } if Instant::now() < until => Err(until - Instant::now()),
+            State::Open { .. } | State::HalfOpen { probing: false } => {
+                *state = State::HalfOpen { probing: true };
+                self.report(BreakerState::HalfOpen);
+                Ok(Permit { breaker: self, probe: true })
+            }
+            State::HalfOpen { probing: true } => Err(PROBE_RETRY_AFTER),
+        }
+        .inspect_err(|_| metrics::get().pg_breaker_rejections.with_label_values(&[self.pool.as_str()]).inc())
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    fn report(&self, state: BreakerState) {
+        metrics::get().pg_breaker_state.with_label_values(&[self.pool.as_str()]).set(state as i64);
+    }
+
+    fn open(&self, state: &mut State) {
+        *state = State::Open { until: Instant::now() + self.cooldown };
+        self.report(BreakerState::Open);
+    }
+}
+
+
+impl BreakerState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::HalfOpen => "half_open",
+            BreakerState::Open => "open",
+        }
+    }
+}
+
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        let breaker = self.breaker;
+        let mut state = breaker.state.lock().unwrap();
+        match *state {
+            State::HalfOpen { .. } if self.probe => {
+                info!("Postgres pool '{}': circuit breaker closed, the probe succeeded", breaker.pool);
+                breaker.report(BreakerState::Closed);
+                *state = State::Closed { failures: 0 };
+            }
+            State::Closed { .. } => *state = State::Closed { failures: 0 },
+            _ => {}
+        }
+        self.probe = false;
+    }
+
+    pub fn failure(mut self) {
+        let breaker = self.breaker;
+        let mut state = breaker.state.lock().unwrap();
+        match *state {
+            State::HalfOpen { .. } if self.probe => {
+                warn!("Postgres pool '{}': circuit breaker reopened, the probe failed, next one in {:?}", breaker.pool, breaker.cooldown);
+                breaker.open(&mut state);
+            }
+            State::Closed { failures } if failures + 1 >= breaker.threshold => {
+                warn!("Postgres pool '{}': circuit breaker opened after {} failed checkouts, next probe in {:?}", breaker.pool, failures + 1, breaker.cooldown);
+                breaker.open(&mut state);
+            }
+            State::Closed { failures } => *state = State::Closed { failures: failures + 1 },
+            _ => {}
+        }
+        self.probe = false;
+    }
+}
+
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        // A probe that ended without an outcome (dropped request, busy pool) lets the next checkout probe
+        if self.probe {
+            let mut state = self.breaker.state.lock().unwrap();
+            if let State::HalfOpen { probing } = &mut *state {
+                *probing = false;
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use crate::models::jobs::{ClaimedRow, Inserted, JobCounts, JobFilter, JobRow, JobStatus, NewJob};
+use super::{query::{Column, FromRow, Query}, retry::idempotent, transaction::Executor, DbError};
+use std::time::Duration;
+use uuid::Uuid;
+
+
+// Jobs listed when the filter gives no limit, and the most it may ask for
+const LIST_LIMIT: i64 = 50;
+const LIST_LIMIT_MAX: i64 = 500;
+
+// Finished jobs deleted at most per purge, a big backlog is spread over several purges
+const PURGE_BATCH: i64 = 1000;
+
+// Inserts tried while the job with the same dedupe key finishes between the conflict and the lookup
+const INSERT_ATTEMPTS: usize = 3;
+
+// A duplicate returns the job already waiting, `created` tells them apart. Nothing is inserted while `$8` jobs are
+// queued, the row then has no ID (counting stops at `$8`, and concurrent inserts may go a little past it).
+const INSERT_JOB: Query = Query {
+    name: "insert_job",
+    sql: r#"
+        WITH room AS (
+            SELECT count(*) < $8 AS free FROM (SELECT 1 FROM jobs WHERE status = 'queued' LIMIT $8) AS queued
+        ),
+        inserted AS (
+            INSERT INTO jobs (id, kind, payload, priority, run_at, dedupe_key, max_attempts)
+            SELECT $1, $2, $3, $4, $5, $6, $7 FROM room WHERE free
+            ON CONFLICT (dedupe_key) WHERE status IN ('queued', 'running') DO NOTHING
+            RETURNING id
+        ),
+        duplicate AS (
+            SELECT id FROM jobs
+            WHERE dedupe_key = $6 AND status IN ('queued', 'running') AND NOT EXISTS (SELECT 1 FROM inserted)
+        )
+        SELECT id, true AS created FROM inserted
+        UNION ALL
+        SELECT id, false AS created FROM duplicate
+        UNION ALL
+        SELECT NULL, false AS created FROM room WHERE NOT free AND NOT EXISTS (SELECT 1 FROM duplicate)
+    "#,
+    columns: &[Column::new::<Option<Uuid>>("id"), Column::new::<bool>("created")],
+    timeout: None,
+};
+
+// Highest priority first, then the longest waiting. Rows locked by another worker's claim are skipped, not waited for.
+const CLAIM_JOBS: Query = Query {
+    name: "claim_jobs",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'running', attempts = attempts + 1, locked_by = $1,
+            locked_until = now() + make_interval(secs => $3), updated_at = now()
+        WHERE id IN (
+            SELECT id FROM jobs
+            WHERE status = 'queued' AND run_at <= now()
+            ORDER BY priority DESC, run_at, id
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, kind, payload, attempts, max_attempts
+    "#,
+    columns: ClaimedRow::COLUMNS,
+    timeout: None,
+};
+
+// The claim still has to be ours: its visibility timeout may have expired and another worker claimed it again
+const COMPLETE_JOB: Query = Query {
+    name: "complete_job",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'succeeded', last_error = NULL, locked_by = NULL, locked_until = NULL,
+            finished_at = now(), updated_at = now()
+        WHERE id = $1 AND status = 'running' AND locked_by = $2 AND attempts = $3
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+const RESCHEDULE_JOB: Query = Query {
+    name: "reschedule_job",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'queued', run_at = now() + make_interval(secs => $4), last_error = $5,
+            locked_by = NULL, locked_until = NULL, updated_at = now()
+        WHERE id = $1 AND status = 'running' AND locked_by = $2 AND attempts = $3
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+const BURY_JOB: Query = Query {
+    name: "bury_job",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'dead', last_error = $4, locked_by = NULL, locked_until = NULL,
+            finished_at = now(), updated_at = now()
+        WHERE id = $1 AND status = 'running' AND locked_by = $2 AND attempts = $3
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+// Claims whose worker stopped or stalled past the visibility timeout, dead once they used all their attempts
+const RECLAIM_EXPIRED: Query = Query {
+    name: "reclaim_expired_jobs",
+    sql: r#"
+        UPDATE jobs
+        SET status = CASE WHEN attempts >= max_attempts THEN 'dead' ELSE 'queued' END,
+            finished_at = CASE WHEN attempts >= max_attempts THEN now() END,
+            last_error = 'visibility timeout expired, the worker running it stopped or stalled',
+            run_at = now(), locked_by = NULL, locked_until = NULL, updated_at = now()
+        WHERE id IN (
+            SELECT id FROM jobs
+            WHERE status = 'running' AND locked_until < now()
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING status
+    "#,
+    columns: &[Column::new::<JobStatus>("status")],
+    timeout: None,
+};
+
+// Claims a stopping worker gave up on, they did not fail so the attempt is not counted
+const RELEASE_JOBS: Query = Query {
+    name: "release_jobs",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'queued', attempts = attempts - 1, run_at = now(),
+            locked_by = NULL, locked_until = NULL, updated_at = now()
+        WHERE status = 'running' AND locked_by = $1
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+const PURGE_JOBS: Query = Query {
+    name: "purge_jobs",
+    sql: r#"
+        DELETE FROM jobs
+        WHERE id IN (
+            SELECT id FROM jobs
+            WHERE status IN ('succeeded', 'dead', 'cancelled') AND finished_at < now() - make_interval(secs => $1)
+            LIMIT $2
+        )
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+const COUNT_JOBS: Query = Query {
+    name: "count_jobs",
+    sql: r#"
+        SELECT
+            count(*) FILTER (WHERE status = 'queued' AND run_at <= now()) AS ready,
+            count(*) FILTER (WHERE status = 'queued' AND run_at > now()) AS scheduled,
+            count(*) FILTER (WHERE status = 'running') AS running,
+            count(*) FILTER (WHERE status = 'dead') AS dead
+        FROM jobs
+        WHERE status IN ('queued', 'running', 'dead')
+    "#,
+    columns: JobCounts::COLUMNS,
+    timeout: Some(Duration::from_secs(5)),
+};
+
+const LIST_JOBS: Query = Query {
+    name: "list_jobs",
+    sql: r#"
+        SELECT id, kind, payload, status, priority, run_at, dedupe_key, attempts, max_attempts, last_error, locked_by,
+            created_at, finished_at
+        FROM jobs
+        WHERE ($1::TEXT IS NULL OR status = $1) AND ($2::TEXT IS NULL OR kind = $2)
+        ORDER BY created_at DESC
+        LIMIT $3
+    "#,
+    columns: JobRow::COLUMNS,
+    timeout: Some(Duration::from_secs(5)),
+};
+
+const SELECT_JOB: Query = Query {
+    name: "select_job",
+    sql: r#"
+        SELECT id, kind, payload, status, priority, run_at, dedupe_key, attempts, max_attempts, last_error, locked_by,
+            created_at, finished_at
+        FROM jobs
+        WHERE id = $1
+    "#,
+    columns: JobRow::COLUMNS,
+    timeout: None,
+};
+
+// A new set of attempts, the last error is kept until the next attempt
+const RETRY_JOB: Query = Query {
+    name: "retry_job",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'queued', attempts = 0, run_at = now(), finished_at = NULL, updated_at = now()
+        WHERE id = $1 AND status IN ('dead', 'cancelled')
+        RETURNING id, kind, payload, status, priority, run_at, dedupe_key, attempts, max_attempts, last_error, locked_by,
+            created_at, finished_at
+    "#,
+    columns: JobRow::COLUMNS,
+    timeout: None,
+};
+
+// Only a job no worker claimed yet, a running job can not be stopped from here
+const CANCEL_JOB: Query = Query {
+    name: "cancel_job",
+    sql: r#"
+        UPDATE jobs
+        SET status = 'cancelled', finished_at = now(), updated_at = now()
+        WHERE id = $1 AND status = 'queued'
+        RETURNING id, kind, payload, status, priority, run_at, dedupe_key, attempts, max_attempts, last_error, locked_by,
+            created_at, finished_at
+    "#,
+    columns: JobRow::COLUMNS,
+    timeout: None,
+};
+
+/// Every query of the module, checked against the schema at startup (once the table exists)
+pub const QUERIES: &[&Query] = &[
+    &INSERT_JOB, &CLAIM_JOBS, &COMPLETE_JOB, &RESCHEDULE_JOB, &BURY_JOB, &RECLAIM_EXPIRED, &RELEASE_JOBS,
+    &PURGE_JOBS, &COUNT_JOBS, &LIST_JOBS, &SELECT_JOB, &RETRY_JOB, &CANCEL_JOB,
+];
+
+
+
+// Insert a job (run it in the caller's transaction to enqueue it only if the transaction commits) unless
+// `max_queued` jobs are queued already or a job with the same dedupe key is waiting
+pub async fn insert_job(db: &impl Executor, id: Uuid, job: &NewJob<'_>, max_queued: i64) -> Result<Inserted, DbError> {
+    let client = db.conn().await?;
+    let params: [&(dyn tokio_postgres::types::ToSql + Sync); 8] =
+        [&id, &job.kind, &job.payload, &job.priority, &job.run_at, &job.dedupe_key, &job.max_attempts, &max_queued];
+
+    for _ in 0..INSERT_ATTEMPTS {
+        // No row when the duplicate finished between the conflict and the lookup, it can be inserted now
+        let Some(row) = client.query_opt(&INSERT_JOB, &params).await? else { continue };
+        return Ok(match (row.try_get("id")?, row.try_get("created")?) {
+            (Some(id), true) => Inserted::Created(id),
+            (Some(id), false) => Inserted::Duplicate(id),
+            (None, _) => Inserted::Full,
+        });
+    }
+    Ok(Inserted::Conflict)
+}
+
+
+// Claim up to `limit` jobs ready to run for `worker`, they are hidden from the other workers for `visibility`
+pub async fn claim_jobs(db: &impl Executor, worker: &str, limit: usize, visibility: Duration) -> Result<Vec<ClaimedRow>, DbError> {
+    let client = db.conn().await?;
+    let rows = client.query(&CLAIM_JOBS, &[&worker, &(limit as i64), &visibility.as_secs_f64()]).await?;
+    Ok(ClaimedRow::from_rows(&rows)?)
+}
+
+
+// Mark a claimed job as succeeded, false if the claim was lost meanwhile
+pub async fn complete_job(db: &impl Executor, job: &ClaimedRow, worker: &str) -> Result<bool, DbError> {
+    let client = db.conn().await?;
+    Ok(client.execute(&COMPLETE_JOB, &[&job.id, &worker, &job.attempts]).await? == 1)
+}
+
+
+// Put a claimed job back in the queue to run again after `delay`, false if the claim was lost meanwhile
+pub async fn reschedule_job(db: &impl Executor, job: &ClaimedRow, worker: &str, delay: Duration, error: &str) -> Result<bool, DbError> {
+    let client = db.conn().await?;
+    Ok(client.execute(&RESCHEDULE_JOB, &[&job.id, &worker, &job.attempts, &delay.as_secs_f64(), &error]).await? == 1)
+}
+
+
+// Mark a claimed job as dead, false if the claim was lost meanwhile
+pub async fn bury_job(db: &impl Executor, job: &ClaimedRow, worker: &str, error: &str) -> Result<bool, DbError> {
+    let client = db.conn().await?;
+    Ok(client.execute(&BURY_JOB, &[&job.id, &worker, &job.attempts, &error]).await? == 1)
+}
+
+
+// Queue again the jobs whose claim expired, returns how many were queued again and how many went dead
+pub async fn reclaim_expired_jobs(db: &impl Executor) -> Result<(usize, usize), DbError> {
+    let client = db.conn().await?;
+    let rows = client.query(&RECLAIM_EXPIRED, &[]).await?;
+    let dead = rows.iter().filter(|row| row.try_get::<_, JobStatus>("status").is_ok_and(|s| s == JobStatus::Dead)).count();
+    Ok((rows.len() - dead, dead))
+}
+
+
+// Queue again the jobs `worker` claimed and did not finish, returns how many
+pub async fn release_jobs(db: &impl Executor, worker: &str) -> Result<u64, DbError> {
+    let client = db.conn().await?;
+    Ok(client.execute(&RELEASE_JOBS, &[&worker]).await?)
+}
+
+
+// Delete a batch of jobs finished more than `retention` ago, returns how many
+pub async fn purge_jobs(db: &impl Executor, retention: Duration) -> Result<u64, DbError> {
+    let client = db.conn().await?;
+    Ok(client.execute(&PURGE_JOBS, &[&retention.as_secs_f64(), &PURGE_BATCH]).await?)
+}
+
+
+// Count the unfinished and dead jobs (a read, retried on a new connection after a transient error)
+pub async fn count_jobs(db: &impl Executor) -> Result<JobCounts, DbError> {
+    idempotent(db, async |client| {
+        let row = client.query_one(&COUNT_JOBS, &[]).await?;
+        Ok(JobCounts::from_row(&row)?)
+    }).await
+}
+
+
+// List the newest jobs matching the filter
+pub async fn list_jobs(db: &impl Executor, filter: &JobFilter) -> Result<Vec<JobRow>, DbError> {
+    let status = filter.status.map(|s| s.as_str());
+    let limit = filter.limit.unwrap_or(LIST_LIMIT).clamp(1, LIST_LIMIT_MAX);
+    idempotent(db, async |client| {
+        let rows = client.query(&LIST_JOBS, &[&status, &filter.kind, &limit]).await?;
+        Ok(JobRow::from_rows(&rows)?)
+    }).await
+}
+
+
+// Fetch a job, None if it does not exist
+pub async fn fetch_job(db: &impl Executor, id: Uuid) -> Result<Option<JobRow>, DbError> {
+    idempotent(db, async |client| {
+        let row = client.query_opt(&SELECT_JOB, &[&id]).await?;
+        Ok(row.as_ref().map(JobRow::from_row).transpose()?)
+    }).await
+}
+
+
+// Queue a dead or cancelled job again with all its attempts, None if there is no such job in one of these states.
+// Fails with a unique violation if a job with the same dedupe key is waiting.
+pub async fn retry_job(db: &impl Executor, id: Uuid) -> Result<Option<JobRow>, DbError> {
+    let client = db.conn().await?;
+    let row = client.query_opt(&RETRY_JOB, &[&id]).await?;
+    Ok(row.as_ref().map(JobRow::from_row).transpose()?)
+}
+
+
+// Cancel a queued job, None if there is no such job waiting
+pub async fn cancel_job(db: &impl Executor, id: Uuid) -> Result<Option<JobRow>, DbError> {
+    let client = db.conn().await?;
+    let row = client.query_opt(&CANCEL_JOB, &[&id]).await?;
+    Ok(row.as_ref().map(JobRow::from_row).transpose()?)
+}
@@ -1,15 +1,130 @@
 use deadpool_postgres::{
     PoolError as PgError,
-    Pool as PgPool
+    Pool as PgPool,
+    TimeoutType,
+    Client
 };
+use tracing::{field::Empty, Instrument};
+use crate::{metrics, telemetry::sanitize_sql};
+use std::{fmt::{self, Display}, future::Future, time::{Duration, Instant}};
 
+pub mod breaker;
+pub mod jobs;
 pub mod notes;
+pub mod pool;
+pub mod query;
+pub mod replicas;
+pub mod retry;
+pub mod timeout;
+pub mod tls;
+pub mod transaction;
+
+
+/// Error of the database layer: one of the pool (checkout or query), or the circuit breaker refusing the checkout
+#[derive(Debug)]
+pub enum DbError {
+    Pool(PgError),
+    Unavailable(Duration),  // Circuit open, time left before the next probe
+}
 
 
 // DB working state Check
-pub async fn health_check(db_pool: &PgPool) -> Result<(), PgError> {
+pub async fn health_check(db_pool: &PgPool) -> Result<(), DbError> {
     // Simple query to check if the database is responsive
-    let client = db_pool.get().await?;
-    let _ = client.query("SELECT 1", &[]).await?;
+    const SQL: &str = "SELECT 1";
+    let client = get_client(db_pool).await?;
+    let _ = traced(SQL, client.query(SQL, &[])).await?;
     Ok(())
 }
+
+
+/// Check a connection out of the pool inside a `db.pool.get` span (waiting for a free one shows up here),
+/// the checkout time and timeouts are counted in the metrics. Fails fast while the pool's circuit breaker is open.
+pub async fn get_client(db_pool: &PgPool) -> Result<Client, DbError> {
+    let breaker = breaker::of(db_pool);
+    let permit = breaker.as_deref().map(breaker::Breaker::acquire).transpose().map_err(DbError::Unavailable)?;
+
+    let span = tracing::info_span!("db.pool.get", db.system.name = "postgresql", otel.status_code = Empty, error.message = Empty);
+    let started = Instant::now();
+    let result = db_pool.get().instrument(span.clone()).await;
+    if let Some(permit) = permit {
+        match &result {
+            Ok(_) => permit.success(),
+            Err(e) if breaker::is_outage(e) => permit.failure(),
+            Err(_) => {}
+        }
+    }
+
+    let metrics = metrics::get();
+    metrics.pg_checkout_duration.observe(started.elapsed().as_secs_f64());
+    if let Err(PgError::Timeout(kind)) = &result {
+        let kind = match kind {
+            TimeoutType::Wait => "wait",
+            TimeoutType::Create => "create",
+            TimeoutType::Recycle => "recycle",
+        };
+        metrics.pg_timeouts.with_label_values(&[kind]).inc();
+    }
+
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        span.record("error.message", tracing::field::display(e));
+    }
+    Ok(result?)
+}
+
+
+/// Run a query inside a client span carrying its sanitized SQL, failures are recorded on the span
+pub async fn traced<T, E, F>(sql: &str, query: F) -> Result<T, E>
+    where F: Future<Output = Result<T, E>>, E: Display
+{
+    let text = sanitize_sql(sql);
+    let operation = text.split(' ').next().unwrap_or_default().to_uppercase();
+    let span = tracing::info_span!(
+        "db.query",
+        otel.name = %operation,
+        otel.kind = "client",
+        otel.status_code = Empty,
+        db.system.name = "postgresql",
+        db.operation.name = %operation,
+        db.query.text = %text,
+        error.message = Empty,
+    );
+
+    let result = query.instrument(span.clone()).await;
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        span.record("error.message", tracing::field::display(e));
+    }
+    result
+}
+
+
+// ------- Implementations ------- //
+
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "{}", e),
+            DbError::Unavailable(d) => write!(f, "Postgres is unavailable (circuit breaker open), retry in {}s", d.as_secs_f64().ceil()),
+        }
+    }
+}
+
+
+impl std::error::Error for DbError {}
+
+
+impl From<PgError> for DbError {
+    fn from(e: PgError) -> Self {
+        DbError::Pool(e)
+    }
+}
+
+
+impl From<tokio_postgres::Error> for DbError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        DbError::Pool(PgError::Backend(e))
+    }
+}
@@ -1,54 +1,76 @@
-use crate::models::notes::Notes;
-use deadpool_postgres::{
-    PoolError as PgError,
-    Pool as PgPool
+use crate::models::notes::{CreateNote, NoteRow, UpdateNote};
+use std::time::Duration;
+use super::{query::{Column, FromRow, Query}, retry::idempotent, transaction::Executor, DbError};
+
+
+const INSERT_NOTE: Query = Query {
+    name: "insert_note",
+    sql: r#"
+        INSERT INTO notes (title, content)
+        VALUES ($1, $2)
+        RETURNING id
+    "#,
+    columns: &[Column::new::<i32>("id")],
+    timeout: None,
 };
 
+const SELECT_NOTES: Query = Query {
+    name: "select_notes",
+    sql: r#"
+        SELECT id, title, content FROM notes
+    "#,
+    columns: NoteRow::COLUMNS,
+    timeout: Some(Duration::from_secs(5)),  // Unbounded, keep it from holding a connection for long
+};
+
+const UPDATE_NOTE: Query = Query {
+    name: "update_note",
+    sql: r#"
+        UPDATE notes
+        SET title = COALESCE($2, title), content = COALESCE($3, content)
+        WHERE id = $1
+        RETURNING id, title, content
+    "#,
+    columns: NoteRow::COLUMNS,
+    timeout: None,
+};
 
+/// Every query of the module, checked against the schema at startup
+pub const QUERIES: &[&Query] = &[&INSERT_NOTE, &SELECT_NOTES, &UPDATE_NOTE];
 
-// Sample private function to create a new note
-async fn create_single_note(db_pool: &PgPool, note: Notes) -> Result<i32, PgError> {
-    let client = db_pool.get().await?;
-    let result = client
-        .query(
-            r#"
-            INSERT INTO notes (title, content)
-            VALUES ($1, $2)
-            RETURNING id
-            "#,
-            &[&note.title, &note.content],
-        )
-        .await?;
-    Ok(result[0].get("id"))
+
+// Sample private function to create a new note (runs on a pool or inside the caller's transaction)
+async fn create_single_note(db: &impl Executor, note: &CreateNote) -> Result<i32, DbError> {
+    let client = db.conn().await?;
+    let row = client.query_one(&INSERT_NOTE, &[&note.title, &note.content]).await?;
+    Ok(row.try_get("id")?)
 }
 
 
-// Add few sample data in DB
-pub async fn add_new_notes(db_pool: &PgPool, values: Vec<Notes>) -> Result<(), PgError> {
+// Add few sample data in DB (run by the `InsertNotes` job), returns how many were inserted
+// (run it in a transaction: all the notes or none of them)
+pub async fn add_new_notes(db: &impl Executor, values: &[CreateNote]) -> Result<usize, DbError> {
+    // We can do like this to purely put the query in one function and call it in another function
+    // We can even do some processing before calling the query (but all db related stuff should be in db module only)
     for note in values {
-        // We can do like this to purely put the query in one function and call it in another function
-        // We can even do some processing before calling the query (but all db related stuff should be in db module only)
-        let pool = db_pool.clone(); // We can even clone the pool and spawn it to be fully parallel
-        tokio::spawn(async move {
-            let _ = create_single_note(&pool, note).await;
-        });
+        create_single_note(db, note).await?;
     }
-
-    Ok(())
+    Ok(values.len())
 }
 
 
-// Fetch all notes from DB
-pub async fn fetch_all_notes(db_pool: &PgPool) -> Result<Vec<Notes>, PgError> {
-    let client = db_pool.get().await?;
-    let rows = client
-        .query(
-            r#"
-            SELECT id, title, content FROM notes
-            "#,
-            &[],
-        )
-        .await?;
+// Fetch all notes from DB (a read, retried on a new connection after a transient error)
+pub async fn fetch_all_notes(db: &impl Executor) -> Result<Vec<NoteRow>, DbError> {
+    idempotent(db, async |client| {
+        let rows = client.query(&SELECT_NOTES, &[]).await?;
+        Ok(NoteRow::from_rows(&rows)?)
+    }).await
+}
+
 
-    Ok(Notes::from_rows(rows))
+// Update the given fields of a note, returns None if the note does not exist
+pub async fn update_single_note(db: &impl Executor, id: i32, note: &UpdateNote) -> Result<Option<NoteRow>, DbError> {
+    let client = db.conn().await?;
+    let row = client.query_opt(&UPDATE_NOTE, &[&id, &note.title, &note.content]).await?;
+    Ok(row.as_ref().map(NoteRow::from_row).transpose()?)
 }
@@ -0,0 +1,159 @@
+use deadpool::managed::Object;
+use deadpool_postgres::{
+    ClientWrapper,
+    HookError,
+    Pool as PgPool,
+    RecyclingMethod,
+    Hook,
+};
+use tokio::task::JoinSet;
+use std::{fmt, str::FromStr, time::Duration};
+use crate::metrics;
+use super::{get_client, timeout};
+use log::{debug, warn};
+
+
+// How often the idle connections are topped up to the minimum
+const MIN_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+
+/// How a pooled connection is checked before it is handed out again (`PG_POOL_RECYCLING_METHOD`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recycling(pub RecyclingMethod);
+
+
+
+/// Check out `n` connections at the same time (creating the missing ones) and hand them back, returns how many succeeded
+pub async fn warm_up(pool: &PgPool, n: usize) -> usize {
+    let mut checkouts = JoinSet::new();
+    for _ in 0..n {
+        let pool = pool.clone();
+        checkouts.spawn(async move {
+            let client = get_client(&pool).await.ok()?;
+            client.simple_query("SELECT 1").await.ok()?;
+            Some(client)
+        });
+    }
+
+    // Keep every connection until all are checked out, a returned one would be handed out again instead of a new one
+    let clients: Vec<_> = checkouts.join_all().await.into_iter().flatten().collect();
+    clients.len()
+}
+
+
+/// Keep up to `min_idle` idle connections (never above the pool size), so a burst does not wait for new connections.
+/// A tick only opens connections while none is idle, see `top_up`.
+pub fn spawn_min_idle(pool: PgPool, name: String, min_idle: usize) {
+    if min_idle == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(MIN_IDLE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let status = pool.status();
+            let missing = min_idle.saturating_sub(status.available).min(status.max_size.saturating_sub(status.size));
+            // Requests waiting for a connection get the new ones anyway
+            if missing == 0 || status.waiting > 0 {
+                continue;
+            }
+
+            let opened = top_up(&pool, missing).await;
+            if opened > 0 {
+                debug!("Pool {}: opened {} idle connections", name, opened);
+            }
+        }
+    });
+}
+
+
+/// Open up to `n` new connections one at a time, holding only the new ones until the end. The pool hands out an
+/// idle connection before creating one, so this stops as soon as one is idle and leaves it to the requests.
+async fn top_up(pool: &PgPool, n: usize) -> usize {
+    let mut opened = Vec::with_capacity(n);
+    while opened.len() < n && pool.status().available == 0 {
+        let Ok(client) = get_client(pool).await else { break };
+        // Another connection came back between the status and the checkout
+        if Object::metrics(&client).recycle_count > 0 {
+            break;
+        }
+        opened.push(client);
+    }
+    opened.len()
+}
+
+
+/// `pre_recycle` hook running the recycling check (the manager itself only checks for closed connections),
+/// failed checks are counted per pool and the connection is dropped. A connection a cancel may still land on is
+/// always dropped.
+pub fn recycle_hook(pool: &str, recycling: &Recycling, timeout: Duration) -> Hook {
+    let pool = pool.to_string();
+    let check = recycling.0.query().map(String::from);
+
+    Hook::async_fn(move |client: &mut ClientWrapper, _| {
+        let pool = pool.clone();
+        let check = check.clone();
+        Box::pin(async move {
+            let failure = if timeout::take_tainted(&client.statement_cache) {
+                Some("cancelled")
+            } else if client.is_closed() {
+                Some("closed")
+            } else if let Some(sql) = &check {
+                match tokio::time::timeout(timeout, client.simple_query(sql)).await {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => {
+                        warn!("Pool {}: connection could not be recycled: {}", pool, e);
+                        Some("error")
+                    }
+                    Err(_) => Some("timeout"),
+                }
+            } else {
+                None
+            };
+
+            match failure {
+                Some(reason) => {
+                    metrics::get().pg_recycle_failures.with_label_values(&[pool.as_str(), reason]).inc();
+                    Err(HookError::message(format!("recycle check failed: {}", reason)))
+                }
+                None => Ok(()),
+            }
+        })
+    })
+}
+
+
+// ------- Implementations ------- //
+
+
+impl FromStr for Recycling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast" => Ok(Recycling(RecyclingMethod::Fast)),
+            "verified" => Ok(Recycling(RecyclingMethod::Verified)),
+            "clean" => Ok(Recycling(RecyclingMethod::Clean)),
+            _ => match s.split_once(':') {
+                Some((prefix, sql)) if prefix.eq_ignore_ascii_case("custom") && !sql.trim().is_empty() => {
+                    Ok(Recycling(RecyclingMethod::Custom(sql.trim().to_string())))
+                }
+                _ => Err(format!("'{}' is not fast, verified, clean or custom:<SQL>", s)),
+            },
+        }
+    }
+}
+
+
+impl fmt::Display for Recycling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            RecyclingMethod::Fast => f.write_str("fast"),
+            RecyclingMethod::Verified => f.write_str("verified"),
+            RecyclingMethod::Clean => f.write_str("clean"),
+            RecyclingMethod::Custom(sql) => write!(f, "custom:{}", sql),
+        }
+    }
+}
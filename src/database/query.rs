@@ -0,0 +1,106 @@
+use tokio_postgres::{error::SqlState, types::{FromSqlOwned, Type}, Error, Row};
+use deadpool_postgres::Pool as PgPool;
+use super::{get_client, timeout::{cancellable, Canceller}, traced, transaction::Conn};
+use std::{future::Future, time::Duration};
+use log::{info, warn};
+
+
+// Time to prepare one query during the startup check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// A statement of the repository with the columns it returns, checked against the schema at startup
+pub struct Query {
+    pub name: &'static str,
+    pub sql: &'static str,
+    pub columns: &'static [Column],
+    pub timeout: Option<Duration>,   // Cancelled past it, the server's statement_timeout still applies
+}
+
+
+/// A column a query returns, with the check that its SQL type decodes into the Rust field
+pub struct Column {
+    pub name: &'static str,
+    accepts: fn(&Type) -> bool,
+}
+
+
+/// Typed mapping of a result row, a missing column or a type mismatch is an error instead of a panic
+pub trait FromRow: Sized {
+    /// Columns read by `from_row`, the queries returning this row declare them
+    const COLUMNS: &'static [Column];
+
+    fn from_row(row: &Row) -> Result<Self, Error>;
+
+    fn from_rows(rows: &[Row]) -> Result<Vec<Self>, Error> {
+        rows.iter().map(Self::from_row).collect()
+    }
+}
+
+
+
+/// Run a declared query on `conn` (prepared and executed by `query`): traced, limited by its timeout and the request's
+/// deadline, cancelled on the server if abandoned
+pub async fn run<T, F>(conn: &Conn<'_>, declared: &Query, query: F) -> Result<T, Error>
+    where F: Future<Output = Result<T, Error>>
+{
+    traced(declared.sql, cancellable(conn.canceller(), declared.timeout, query)).await
+}
+
+
+/// Prepare every query on a connection of the pool and compare the columns it returns with the declared ones.
+/// Skipped with a warning when the DB can not be reached (the health checks report that) or a table stays locked,
+/// errors list every mismatch.
+pub async fn check_queries(db_pool: &PgPool, queries: &[&Query]) -> Result<(), String> {
+    let client = match get_client(db_pool).await {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Skipping the query check, no connection to Postgres: {}", e);
+            return Ok(());
+        }
+    };
+
+    let mut problems = Vec::new();
+    for query in queries {
+        // Preparing waits for the locks on the tables, a locked table must not hold the startup
+        let canceller = Canceller::new(client.cancel_token(), &client.statement_cache);
+        let statement = match cancellable(canceller, Some(CHECK_TIMEOUT), client.prepare(query.sql)).await {
+            Ok(statement) => statement,
+            Err(e) if e.code() == Some(&SqlState::QUERY_CANCELED) => {
+                warn!("Skipping the query check, preparing {} took more than {:?}", query.name, CHECK_TIMEOUT);
+                return Ok(());
+            }
+            Err(e) => {
+                let reason = e.as_db_error().map(|e| e.message().to_string()).unwrap_or_else(|| e.to_string());
+                problems.push(format!("{}: {}", query.name, reason));
+                continue;
+            }
+        };
+
+        for expected in query.columns {
+            match statement.columns().iter().find(|c| c.name() == expected.name) {
+                Some(column) if (expected.accepts)(column.type_()) => {}
+                Some(column) => problems.push(format!("{}: column '{}' is {}, its field can not hold it", query.name, expected.name, column.type_())),
+                None => problems.push(format!("{}: does not return the column '{}'", query.name, expected.name)),
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    info!("{} queries match the database schema", queries.len());
+    Ok(())
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Column {
+    /// Column read into a field of type `T`
+    pub const fn new<T: FromSqlOwned>(name: &'static str) -> Self {
+        Column { name, accepts: T::accepts }
+    }
+}
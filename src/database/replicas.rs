@@ -0,0 +1,230 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use deadpool_postgres::Pool as PgPool;
+use std::time::Duration;
+use actix_web::web::Data as webData;
+use serde_json::{json, Value};
+use moka::future::Cache;
+use super::{breaker, get_client, query::{Column, Query}};
+use log::{info, warn};
+
+
+// Replication lag in seconds, 0 when a streaming replica replayed everything it received (an idle primary is not lag)
+// and on a server that is not in recovery, so a replica URL may point at the primary. A disconnected replica has no
+// WAL receiver or one that is not streaming, its replay position then stops moving and would look caught up.
+// The receiver status is hidden from roles without pg_read_all_stats, the lag is then taken from the last replay.
+const REPLICA_LAG: Query = Query {
+    name: "replica_lag",
+    sql: r#"
+        WITH receiver AS (SELECT status FROM pg_stat_wal_receiver)
+        SELECT
+            pg_is_in_recovery() AND NOT EXISTS (SELECT 1 FROM receiver) AS no_receiver,
+            (SELECT status FROM receiver) AS receiver_status,
+            CASE
+                WHEN NOT pg_is_in_recovery() THEN 0
+                WHEN (SELECT status FROM receiver) = 'streaming' AND pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+                ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0)
+            END::float8 AS lag
+    "#,
+    columns: &[Column::new::<bool>("no_receiver"), Column::new::<Option<String>>("receiver_status"), Column::new::<f64>("lag")],
+    timeout: None,
+};
+
+/// Queries of the replica monitor, they run on any server so the startup check prepares them on the primary
+pub const QUERIES: &[&Query] = &[&REPLICA_LAG];
+
+// Sessions that wrote recently, at most this many are remembered
+const MAX_STICKY_SESSIONS: u64 = 100_000;
+
+
+/// A read replica, its health and lag are updated by the monitor
+pub struct Replica {
+    name: String,
+    pool: PgPool,
+    weight: u32,
+    healthy: AtomicBool,
+    lag_ms: AtomicU64,
+    error: std::sync::Mutex<Option<String>>,
+}
+
+
+/// Primary and replica pools: reads go to a healthy replica (weighted), writes and fallbacks to the primary
+pub struct ReplicaSet {
+    primary: PgPool,
+    replicas: Vec<Replica>,
+    max_lag: Duration,
+    next: AtomicU64,
+    sticky: Option<Cache<String, ()>>,  // Sessions reading from the primary after a write (read-your-writes)
+    checked: AtomicBool,                // Every replica was checked once
+}
+
+
+
+/// Lag of one replica, an error if it can not be reached or does not receive the WAL of the primary
+async fn replica_lag(pool: &PgPool) -> Result<Duration, String> {
+    let client = get_client(pool).await.map_err(|e| e.to_string())?;
+    let row = client.query_one(REPLICA_LAG.sql, &[]).await.map_err(|e| e.to_string())?;
+
+    if row.try_get::<_, bool>("no_receiver").map_err(|e| e.to_string())? {
+        return Err("no WAL receiver, the replica is not connected to the primary".to_string());
+    }
+    match row.try_get::<_, Option<String>>("receiver_status").map_err(|e| e.to_string())? {
+        Some(status) if status != "streaming" => Err(format!("WAL receiver is {}, not streaming from the primary", status)),
+        _ => Ok(Duration::from_secs_f64(row.try_get::<_, f64>("lag").map_err(|e| e.to_string())?.max(0.0))),
+    }
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Replica {
+    pub fn new(name: String, pool: PgPool, weight: u32) -> Self {
+        // Unhealthy until the first check, reads stay on the primary meanwhile
+        Replica { name, pool, weight, healthy: AtomicBool::new(false), lag_ms: AtomicU64::new(0), error: Default::default() }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+}
+
+
+impl ReplicaSet {
+    /// `read_your_writes` is how long a session keeps reading from the primary after a write, zero disables it
+    pub fn new(primary: PgPool, replicas: Vec<Replica>, max_lag: Duration, read_your_writes: Duration) -> Self {
+        let sticky = (!replicas.is_empty() && !read_your_writes.is_zero()).then(|| {
+            Cache::builder().max_capacity(MAX_STICKY_SESSIONS).time_to_live(read_your_writes).build()
+        });
+        let checked = AtomicBool::new(replicas.is_empty());
+        ReplicaSet { primary, replicas, max_lag, next: AtomicU64::new(0), sticky, checked }
+    }
+
+    pub fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// True once the monitor checked every replica, healthy or not, so reads are routed on their real state
+    pub fn is_checked(&self) -> bool {
+        self.checked.load(Ordering::Relaxed)
+    }
+
+    /// Every pool with its name, the primary first
+    pub fn pools(&self) -> impl Iterator<Item = (&str, &PgPool)> {
+        std::iter::once(("primary", &self.primary)).chain(self.replicas.iter().map(|r| (r.name.as_str(), &r.pool)))
+    }
+
+    /// Pool for a read-only query, the primary if the session wrote recently or no replica is usable
+    pub fn reader(&self, session: Option<&str>) -> &PgPool {
+        if let (Some(sticky), Some(session)) = (&self.sticky, session)
+            && sticky.contains_key(session)
+        {
+            return &self.primary;
+        }
+
+        let total: u64 = self.replicas.iter().filter(|r| r.is_healthy()).map(|r| u64::from(r.weight)).sum();
+        if total == 0 {
+            return &self.primary;
+        }
+
+        // Weighted round robin over the healthy replicas
+        let mut slot = self.next.fetch_add(1, Ordering::Relaxed) % total;
+        for replica in self.replicas.iter().filter(|r| r.is_healthy()) {
+            if slot < u64::from(replica.weight) {
+                return &replica.pool;
+            }
+            slot -= u64::from(replica.weight);
+        }
+        &self.primary
+    }
+
+    /// Remember that the session wrote, its next reads see the write
+    pub async fn mark_write(&self, session: &str) {
+        if let Some(sticky) = &self.sticky {
+            sticky.insert(session.to_string(), ()).await;
+        }
+    }
+
+    /// Check the lag of every replica now and then, a lagging or unreachable replica gets no reads
+    pub fn spawn_monitor(replicas: webData<ReplicaSet>, interval: Duration) {
+        if replicas.replicas.is_empty() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                for replica in &replicas.replicas {
+                    replicas.check(replica, interval).await;
+                }
+                replicas.checked.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    async fn check(&self, replica: &Replica, timeout: Duration) {
+        let result = match tokio::time::timeout(timeout, replica_lag(&replica.pool)).await {
+            Ok(Ok(lag)) if lag > self.max_lag => Err(format!("lagging {:.1}s behind the primary", lag.as_secs_f64())),
+            Ok(Ok(lag)) => Ok(lag),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(format!("lag check timed out after {:?}", timeout)),
+        };
+
+        let was_healthy = replica.is_healthy();
+        match result {
+            Ok(lag) => {
+                replica.lag_ms.store(lag.as_millis() as u64, Ordering::Relaxed);
+                replica.healthy.store(true, Ordering::Relaxed);
+                *replica.error.lock().unwrap() = None;
+                if !was_healthy {
+                    info!("Replica {} is serving reads (lag {:?})", replica.name, lag);
+                }
+            }
+            Err(e) => {
+                replica.healthy.store(false, Ordering::Relaxed);
+                if was_healthy {
+                    warn!("Replica {} removed from reads: {}", replica.name, e);
+                }
+                *replica.error.lock().unwrap() = Some(e);
+            }
+        }
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Check every replica now (one after the other) and report them, an error when none of them serves reads
+    pub async fn health(&self, timeout: Duration) -> Result<Value, String> {
+        for replica in &self.replicas {
+            self.check(replica, timeout).await;
+        }
+
+        let details: Vec<Value> = self.replicas.iter().map(|r| {
+            let status = r.pool.status();
+            json!({
+                "name": r.name,
+                "healthy": r.is_healthy(),
+                "weight": r.weight,
+                "lag_ms": r.lag_ms.load(Ordering::Relaxed),
+                "size": status.size,
+                "available": status.available,
+                "breaker": breaker::of(&r.pool).map(|b| b.state().as_str()),
+                "error": r.error.lock().unwrap().clone(),
+            })
+        }).collect();
+
+        if self.replicas.iter().any(Replica::is_healthy) {
+            Ok(json!({ "replicas": details }))
+        } else {
+            Err(format!("no replica serves reads, falling back to the primary: {}", Value::from(details)))
+        }
+    }
+
+    pub fn close(&self) {
+        for replica in &self.replicas {
+            replica.pool.close();
+        }
+    }
+}
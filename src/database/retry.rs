@@ -0,0 +1,88 @@
+use deadpool_postgres::{PoolError as PgError, TimeoutType};
+use tokio_postgres::error::SqlState;
+use std::{sync::OnceLock, time::Duration};
+use super::{transaction::{Conn, Executor}, DbError};
+use crate::metrics;
+
+
+// Backoff between two attempts of a read never goes above it
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Set once at startup, no retries until then
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+
+/// How idempotent reads are retried after a transient error (`PG_RETRY_ATTEMPTS`, `PG_RETRY_BACKOFF`)
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,      // Attempts after the first one, 0 disables the retries
+    pub backoff: Duration,  // Before the first retry, doubled on every retry
+}
+
+
+
+/// Set the retry policy of the reads (the first one wins)
+pub fn set_policy(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+
+/// Backoff before the given retry (1 based): `base` doubled every time up to `max`, with random jitter of up to as much
+/// so the retrying clients do not collide again
+pub fn backoff(base: Duration, max: Duration, retry: u32) -> Duration {
+    let delay = base.saturating_mul(1 << retry.saturating_sub(1).min(16)).min(max);
+    let jitter = (uuid::Uuid::new_v4().as_u128() % (delay.as_micros() + 1)) as u64;
+    delay + Duration::from_micros(jitter)
+}
+
+
+/// True for the errors the same read can succeed after: lost or refused connections, a server shutting down
+/// or starting up (failover), a conflict with a concurrent transaction. Timeouts and an open breaker are final.
+pub fn is_transient(e: &DbError) -> bool {
+    let e = match e {
+        DbError::Pool(PgError::Timeout(TimeoutType::Create | TimeoutType::Recycle)) => return true,
+        DbError::Pool(PgError::Backend(e)) => e,
+        _ => return false,
+    };
+    if e.is_closed() || std::error::Error::source(e).is_some_and(|source| source.is::<std::io::Error>()) {
+        return true;
+    }
+
+    let Some(code) = e.code() else { return false };
+    code.code().starts_with("08")  // Connection exception class
+        || [
+            SqlState::ADMIN_SHUTDOWN,
+            SqlState::CRASH_SHUTDOWN,
+            SqlState::CANNOT_CONNECT_NOW,
+            SqlState::TOO_MANY_CONNECTIONS,
+            SqlState::T_R_SERIALIZATION_FAILURE,
+            SqlState::T_R_DEADLOCK_DETECTED,
+        ].contains(code)
+}
+
+
+/// Run a read that can safely run twice on a connection of `db`: after a transient error it runs again on a new
+/// connection, per the retry policy. Inside a transaction it runs once, the error aborted the transaction.
+pub async fn idempotent<T, F>(db: &impl Executor, mut read: F) -> Result<T, DbError>
+    where F: AsyncFnMut(&Conn<'_>) -> Result<T, DbError>
+{
+    let attempts = match POLICY.get() {
+        Some(policy) if db.retries_reads() => policy.attempts,
+        _ => 0,
+    };
+
+    let mut retry = 0;
+    loop {
+        let result = async { read(&db.conn().await?).await }.await;
+        match result {
+            Err(e) if retry < attempts && is_transient(&e) => {
+                retry += 1;
+                let delay = backoff(POLICY.get().map(|p| p.backoff).unwrap_or_default(), BACKOFF_MAX, retry);
+                log::warn!("Transient database error, retry {}/{} of the read in {:?}: {}", retry, attempts, delay, e);
+                metrics::get().pg_read_retries.inc();
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use tokio_postgres::{error::SqlState, CancelToken, Error, NoTls};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, Weak};
+use std::{future::Future, pin::pin, time::Duration};
+use deadpool_postgres::StatementCache;
+use super::tls::MakeRustlsConnect;
+use tokio::time::Instant;
+
+
+// TLS of the cancel requests, they go through a new connection secured like the pool ones
+static CANCEL_TLS: OnceLock<MakeRustlsConnect> = OnceLock::new();
+
+// Connections a cancel was sent to without seeing it land, it could still hit their next query. The pool drops them
+// instead of handing them out again. They are known by their statement cache (one per connection living as long as
+// it), a weak reference keeps its address from being reused and tells when the connection is gone.
+static TAINTED: LazyLock<Mutex<Vec<Weak<StatementCache>>>> = LazyLock::new(Default::default);
+
+
+tokio::task_local! {
+    // When the queries of the current request have to be done by
+    static DEADLINE: Instant;
+}
+
+
+/// Cancels the running query of a connection, see `Conn::canceller`
+pub struct Canceller {
+    token: CancelToken,
+    conn: Weak<StatementCache>,
+}
+
+
+/// Cancels the running query on the server unless disarmed (the query ended)
+struct CancelOnDrop(Option<Canceller>);
+
+
+
+/// Set the TLS connector used for the cancel requests (the first one wins, every pool shares the TLS settings)
+pub fn set_cancel_tls(tls: MakeRustlsConnect) {
+    let _ = CANCEL_TLS.set(tls);
+}
+
+
+/// Run a request with a deadline for all its queries
+pub async fn with_deadline<F>(timeout: Duration, request: F) -> F::Output
+    where F: Future
+{
+    DEADLINE.scope(Instant::now() + timeout, request).await
+}
+
+
+/// Whether the connection of `statement_cache` was tainted by a cancel, it is forgotten once asked
+pub fn take_tainted(statement_cache: &Arc<StatementCache>) -> bool {
+    let mut tainted = TAINTED.lock().unwrap();
+    forget_closed(&mut tainted);
+    match tainted.iter().position(|conn| std::ptr::eq(conn.as_ptr(), Arc::as_ptr(statement_cache))) {
+        Some(i) => {
+            tainted.swap_remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+
+// Drop the tainted connections that were closed without going back to the pool
+fn forget_closed(tainted: &mut Vec<Weak<StatementCache>>) {
+    tainted.retain(|conn| conn.strong_count() > 0);
+}
+
+
+async fn cancel(token: CancelToken) {
+    let result = match CANCEL_TLS.get() {
+        Some(tls) => token.cancel_query(tls.clone()).await,
+        None => token.cancel_query(NoTls).await,
+    };
+    if let Err(e) = result {
+        log::warn!("Could not cancel a Postgres query: {}", e);
+    }
+}
+
+
+/// Run `query` with a time limit, its own `timeout` or what is left of the request's deadline (the shorter one).
+/// Past it the query is cancelled on the server and fails with `query_canceled`, waiting for that keeps the connection
+/// usable. If the future is dropped instead (the client went away) the query is cancelled in the background, and the
+/// connection is dropped by the pool instead of being handed out again.
+pub async fn cancellable<T, F>(canceller: Canceller, timeout: Option<Duration>, query: F) -> Result<T, Error>
+    where F: Future<Output = Result<T, Error>>
+{
+    let left = DEADLINE.try_with(|deadline| deadline.saturating_duration_since(Instant::now())).ok();
+    let limit = timeout.into_iter().chain(left).min();
+
+    let mut guard = CancelOnDrop(Some(canceller));
+    let mut query = pin!(query);
+    let result = match limit {
+        Some(limit) => match tokio::time::timeout(limit, &mut query).await {
+            Ok(result) => result,
+            Err(_) => {
+                let Some(canceller) = guard.0.take() else { unreachable!("the guard is armed until the query ends") };
+                cancel(canceller.token.clone()).await;
+                let result = query.await;
+                // The query ended on its own before the cancel landed, which may still happen later
+                if !matches!(&result, Err(e) if e.code() == Some(&SqlState::QUERY_CANCELED)) {
+                    canceller.taint();
+                }
+                result
+            }
+        },
+        None => query.await,
+    };
+
+    guard.0 = None;
+    result
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Canceller {
+    /// Canceller of the connection owning `statement_cache`
+    pub fn new(token: CancelToken, statement_cache: &Arc<StatementCache>) -> Self {
+        Canceller { token, conn: Arc::downgrade(statement_cache) }
+    }
+
+    fn taint(&self) {
+        let mut tainted = TAINTED.lock().unwrap();
+        forget_closed(&mut tainted);
+        if !tainted.iter().any(|conn| conn.ptr_eq(&self.conn)) {
+            tainted.push(self.conn.clone());
+        }
+    }
+}
+
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        // Tainted before the connection goes back to the pool, the cancel may land on whatever it runs next
+        let Some(canceller) = self.0.take() else { return };
+        canceller.taint();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(cancel(canceller.token));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use deadpool_postgres::{Manager, ManagerConfig, Pool as PgPool, RecyclingMethod};
+    use crate::database::{get_client, pool::{recycle_hook, Recycling}, query::Query, transaction::Conn};
+    use crate::metrics;
+    use tokio_postgres::NoTls;
+    use std::time::Duration;
+
+    const SLEEP: Query = Query { name: "sleep", sql: "SELECT pg_sleep(2)", columns: &[], timeout: None };
+
+    // A single connection pool, so the next checkout would get the connection of the abandoned query back
+    fn pool(url: &str) -> PgPool {
+        let mgr = Manager::from_config(url.parse().unwrap(), NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+        PgPool::builder(mgr)
+            .max_size(1)
+            .pre_recycle(recycle_hook("timeout_test", &Recycling::default(), Duration::from_secs(1)))
+            .build()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres, run with TEST_DATABASE_URL=postgres://... cargo test -- --ignored"]
+    async fn abandoned_query_connection_is_not_handed_out_again() {
+        let pool = pool(&std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is set"));
+        let backend_pid = async |conn: &Conn<'_>| {
+            let Conn::Pooled(client) = conn else { unreachable!() };
+            client.query_one("SELECT pg_backend_pid()", &[]).await.unwrap().get::<_, i32>(0)
+        };
+
+        let conn = Conn::Pooled(Box::new(get_client(&pool).await.unwrap()));
+        let abandoned_pid = backend_pid(&conn).await;
+        // Dropped mid-query, as when the client of the request goes away
+        assert!(tokio::time::timeout(Duration::from_millis(100), conn.query(&SLEEP, &[])).await.is_err());
+        drop(conn);
+
+        let conn = Conn::Pooled(Box::new(get_client(&pool).await.unwrap()));
+        assert_ne!(backend_pid(&conn).await, abandoned_pid, "the abandoned connection was handed out again");
+        // The late cancel of the abandoned query must not hit this one
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        conn.query(&SLEEP, &[]).await.expect("the next checkout is not cancelled");
+        assert_eq!(metrics::get().pg_recycle_failures.with_label_values(&["timeout_test", "cancelled"]).get(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect, TlsStream};
+use crate::models::initial::PgTlsSettings;
+use std::{fmt, future::Future, io, pin::Pin, str::FromStr, sync::Arc, task::{Context, Poll}};
+
+
+/// `sslmode` of the Postgres connections, same meaning as in libpq
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub enum SslMode {
+    Disable,
+    #[default]
+    Prefer,     // TLS if the server supports it, the certificate is not checked
+    Require,    // TLS or fail, with a CA bundle the certificate is checked like verify-ca
+    VerifyCa,   // The certificate must be signed by the CA bundle
+    VerifyFull, // ... and be issued for the host name
+}
+
+
+/// Makes the rustls connector of every new pool connection
+#[derive(Clone)]
+pub struct MakeRustlsConnect {
+    config: Arc<ClientConfig>,
+}
+
+
+/// Handshake of a single connection
+pub struct RustlsConnect {
+    config: Arc<ClientConfig>,
+    server_name: io::Result<ServerName<'static>>,   // Unix sockets have none, but they never get to the handshake
+}
+
+
+/// Encrypted connection to Postgres
+pub struct RustlsStream<S>(tokio_rustls::client::TlsStream<S>);
+
+
+/// Checks the chain against the CA bundle, but not the host name (`verify-ca`, `require` with a bundle)
+#[derive(Debug)]
+struct CaOnlyVerifier(Arc<WebPkiServerVerifier>);
+
+
+/// Accepts any certificate, the connection is encrypted but not authenticated (`prefer` / `require`)
+#[derive(Debug)]
+struct NoCertVerifier(Arc<CryptoProvider>);
+
+
+
+fn invalid_data(what: &str, e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, e))
+}
+
+
+/// Load the CA bundle, every certificate of the PEM file is trusted
+fn load_roots(path: &std::path::Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid_data(&path.display().to_string(), e))? {
+        let cert = cert.map_err(|e| invalid_data(&path.display().to_string(), e))?;
+        roots.add(cert).map_err(|e| invalid_data(&path.display().to_string(), e))?;
+    }
+    Ok(roots)
+}
+
+
+/// Build the rustls client config for the ssl mode, CA bundle and client certificate of the settings
+pub fn make_tls_connect(tls: &PgTlsSettings) -> io::Result<MakeRustlsConnect> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let roots = tls.root_cert.as_deref().map(load_roots).transpose()?.map(Arc::new);
+    let verifier: Arc<dyn ServerCertVerifier> = match (tls.mode, roots) {
+        (SslMode::VerifyFull, Some(roots)) => {
+            WebPkiServerVerifier::builder_with_provider(roots, provider.clone()).build().map_err(io::Error::other)?
+        }
+        (SslMode::Require | SslMode::VerifyCa, Some(roots)) => {
+            Arc::new(CaOnlyVerifier(WebPkiServerVerifier::builder_with_provider(roots, provider.clone()).build().map_err(io::Error::other)?))
+        }
+        (SslMode::VerifyCa | SslMode::VerifyFull, None) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "verify-ca and verify-full need a CA bundle (PG_SSL_ROOT_CERT)"));
+        }
+        _ => Arc::new(NoCertVerifier(provider)),
+    };
+    let builder = builder.dangerous().with_custom_certificate_verifier(verifier);
+
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| invalid_data(&cert_path.display().to_string(), e))?;
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(&key_path.display().to_string(), e))?;
+            builder.with_client_auth_cert(certs, key).map_err(|e| invalid_data("client certificate and key do not match", e))?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(MakeRustlsConnect { config: Arc::new(config) })
+}
+
+
+// ------- Implementations ------- //
+
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            other => Err(format!("'{}' is not disable, prefer, require, verify-ca or verify-full", other)),
+        }
+    }
+}
+
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        })
+    }
+}
+
+
+impl SslMode {
+    /// What tokio-postgres negotiates, the certificate checks are done by our verifiers
+    pub fn negotiation(self) -> tokio_postgres::config::SslMode {
+        match self {
+            SslMode::Disable => tokio_postgres::config::SslMode::Disable,
+            SslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => tokio_postgres::config::SslMode::Require,
+        }
+    }
+}
+
+
+impl<S> MakeTlsConnect<S> for MakeRustlsConnect
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    type Stream = RustlsStream<S>;
+    type TlsConnect = RustlsConnect;
+    type Error = io::Error;
+
+    fn make_tls_connect(&mut self, domain: &str) -> io::Result<RustlsConnect> {
+        let server_name = ServerName::try_from(domain.to_string()).map_err(|e| invalid_data(domain, e));
+        Ok(RustlsConnect { config: self.config.clone(), server_name })
+    }
+}
+
+
+impl<S> TlsConnect<S> for RustlsConnect
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    type Stream = RustlsStream<S>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<RustlsStream<S>>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            let stream = tokio_rustls::TlsConnector::from(self.config).connect(self.server_name?, stream).await?;
+            Ok(RustlsStream(stream))
+        })
+    }
+}
+
+
+impl<S> TlsStream for RustlsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    // SCRAM works without channel binding unless the server demands it
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+
+impl<S> AsyncRead for RustlsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+
+impl<S> AsyncWrite for RustlsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+
+impl ServerCertVerifier for CaOnlyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. })) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+
+impl ServerCertVerifier for NoCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
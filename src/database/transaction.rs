@@ -0,0 +1,180 @@
+use deadpool_postgres::{
+    PoolError as PgError,
+    Pool as PgPool,
+    Transaction,
+    Client
+};
+use tokio_postgres::{error::SqlState, types::ToSql, Error, IsolationLevel, Row};
+use tracing::{field::Empty, Instrument};
+use std::{future::Future, time::Duration};
+use super::{get_client, query::{run, Query}, retry::backoff, timeout::Canceller, DbError};
+
+
+// Retries after a serialization failure or deadlock, the backoff doubles every time (plus up to as much jitter)
+const DEFAULT_RETRIES: u32 = 3;
+const BACKOFF_BASE: Duration = Duration::from_millis(20);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+
+/// How `with_transaction` starts the transaction, the default is READ COMMITTED, read-write, retried 3 times
+/// (`TxOptions { isolation: IsolationLevel::Serializable, ..Default::default() }` for read-modify-write work)
+#[derive(Clone, Copy)]
+pub struct TxOptions {
+    pub isolation: IsolationLevel,
+    pub read_only: bool,
+    pub deferrable: bool,  // Only meaningful for a SERIALIZABLE READ ONLY transaction (waits for a safe snapshot)
+    pub retries: u32,      // How many times a conflicting transaction is run again, 0 disables the retries
+}
+
+
+/// Connection a repository function runs its queries on (same query methods as a client), see `Executor`
+pub enum Conn<'a> {
+    Pooled(Box<Client>),
+    Tx(&'a Transaction<'a>),
+}
+
+
+/// What repository functions take: a pool (a connection is checked out per call) or an open transaction
+pub trait Executor: Sync {
+    fn conn(&self) -> impl Future<Output = Result<Conn<'_>, DbError>> + Send;
+
+    /// Whether a failed read can run again on a new connection (not inside a transaction, the error aborted it)
+    fn retries_reads(&self) -> bool;
+}
+
+
+
+/// True for the errors a transaction can be retried after (the other side of the conflict committed)
+pub fn is_retryable(e: &DbError) -> bool {
+    let DbError::Pool(PgError::Backend(e)) = e else { return false };
+    matches!(e.code(), Some(code) if *code == SqlState::T_R_SERIALIZATION_FAILURE || *code == SqlState::T_R_DEADLOCK_DETECTED)
+}
+
+
+/// Run `work` inside a transaction on a connection of the pool: committed if it returns Ok, rolled back otherwise.
+/// On a serialization failure or deadlock (from `work` or the commit) the whole transaction is run again after a backoff,
+/// so `work` must not have side effects outside the DB.
+pub async fn with_transaction<T, F>(db_pool: &PgPool, options: TxOptions, mut work: F) -> Result<T, DbError>
+    where F: AsyncFnMut(&Transaction<'_>) -> Result<T, DbError>
+{
+    let span = tracing::info_span!("db.transaction", db.system.name = "postgresql", attempts = Empty, otel.status_code = Empty, error.message = Empty);
+    let mut client = get_client(db_pool).await?;
+
+    let mut attempt = 0;
+    let result = async {
+        loop {
+            attempt += 1;
+            let result = async {
+                let tx = client.build_transaction()
+                    .isolation_level(options.isolation)
+                    .read_only(options.read_only)
+                    .deferrable(options.deferrable)
+                    .start()
+                    .await?;
+
+                // Dropping the transaction on an error rolls it back
+                let value = work(&tx).await?;
+                tx.commit().await?;
+                Ok(value)
+            }.await;
+
+            match result {
+                Err(e) if attempt <= options.retries && is_retryable(&e) => {
+                    let delay = backoff(BACKOFF_BASE, BACKOFF_MAX, attempt);
+                    log::warn!("Transaction conflict, retry {}/{} in {:?}: {}", attempt, options.retries, delay, e);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }.instrument(span.clone()).await;
+
+    span.record("attempts", attempt);
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        span.record("error.message", tracing::field::display(e));
+    }
+    result
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        TxOptions { isolation: IsolationLevel::ReadCommitted, read_only: false, deferrable: false, retries: DEFAULT_RETRIES }
+    }
+}
+
+
+impl Conn<'_> {
+    /// Run a declared query, prepared once per connection (a transaction shares the statement cache of its connection),
+    /// traced and limited by its timeout and the request's deadline
+    pub async fn query(&self, query: &Query, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, Error> {
+        run(self, query, async {
+            match self {
+                Conn::Pooled(client) => client.query(&client.prepare_cached(query.sql).await?, params).await,
+                Conn::Tx(tx) => tx.query(&tx.prepare_cached(query.sql).await?, params).await,
+            }
+        }).await
+    }
+
+    pub async fn query_one(&self, query: &Query, params: &[&(dyn ToSql + Sync)]) -> Result<Row, Error> {
+        run(self, query, async {
+            match self {
+                Conn::Pooled(client) => client.query_one(&client.prepare_cached(query.sql).await?, params).await,
+                Conn::Tx(tx) => tx.query_one(&tx.prepare_cached(query.sql).await?, params).await,
+            }
+        }).await
+    }
+
+    pub async fn query_opt(&self, query: &Query, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, Error> {
+        run(self, query, async {
+            match self {
+                Conn::Pooled(client) => client.query_opt(&client.prepare_cached(query.sql).await?, params).await,
+                Conn::Tx(tx) => tx.query_opt(&tx.prepare_cached(query.sql).await?, params).await,
+            }
+        }).await
+    }
+
+    /// Run a declared statement that returns no rows, returns how many rows it changed
+    pub async fn execute(&self, query: &Query, params: &[&(dyn ToSql + Sync)]) -> Result<u64, Error> {
+        run(self, query, async {
+            match self {
+                Conn::Pooled(client) => client.execute(&client.prepare_cached(query.sql).await?, params).await,
+                Conn::Tx(tx) => tx.execute(&tx.prepare_cached(query.sql).await?, params).await,
+            }
+        }).await
+    }
+
+    /// Cancels the query running on this connection
+    pub fn canceller(&self) -> Canceller {
+        match self {
+            Conn::Pooled(client) => Canceller::new(client.cancel_token(), &client.statement_cache),
+            Conn::Tx(tx) => Canceller::new(tx.cancel_token(), &tx.statement_cache),
+        }
+    }
+}
+
+
+impl Executor for PgPool {
+    async fn conn(&self) -> Result<Conn<'_>, DbError> {
+        Ok(Conn::Pooled(Box::new(get_client(self).await?)))
+    }
+
+    fn retries_reads(&self) -> bool {
+        true
+    }
+}
+
+
+impl Executor for Transaction<'_> {
+    async fn conn(&self) -> Result<Conn<'_>, DbError> {
+        Ok(Conn::Tx(self))
+    }
+
+    fn retries_reads(&self) -> bool {
+        false
+    }
+}
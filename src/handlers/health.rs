@@ -0,0 +1,158 @@
+use crate::{database::{breaker, health_check as db_health_check_pgsql, replicas::ReplicaSet}, jobs::JobQueue, utils::{make_key, AppCache}};
+use std::{future::Future, time::{Duration, Instant}};
+use deadpool_postgres::Pool as PgPool;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Degraded,
+    Down,
+}
+
+
+/// Result of one component check
+#[derive(Serialize, ToSchema, Clone)]
+pub struct ComponentHealth {
+    pub name: String,
+    pub status: Status,
+    pub critical: bool,         // A critical component being down makes the service not ready
+    pub latency_ms: f64,
+    #[schema(value_type = Object)]
+    pub details: Value,
+    pub error: Option<String>,
+}
+
+
+/// Aggregated health of all the components
+#[derive(Serialize, ToSchema, Clone)]
+pub struct HealthReport {
+    pub status: Status,
+    pub cached: bool,           // True if served from the short-lived result cache
+    pub components: Vec<ComponentHealth>,
+}
+
+
+/// Runs the component checks with a timeout and caches the report briefly so probes don't hammer Postgres
+pub struct HealthChecker {
+    check_timeout: Duration,
+    cache_ttl: Duration,
+    last: Mutex<Option<(Instant, HealthReport)>>,
+}
+
+
+
+/// Run one check with the timeout, measuring its latency
+async fn run_check<F>(name: &str, critical: bool, timeout: Duration, check: F) -> ComponentHealth
+    where F: Future<Output = Result<Value, String>>
+{
+    let start = Instant::now();
+    let result = tokio::time::timeout(timeout, check).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let (status, details, error) = match result {
+        Ok(Ok(details)) => (Status::Up, details, None),
+        Ok(Err(e)) => (Status::Down, Value::Null, Some(e)),
+        Err(_) => (Status::Down, Value::Null, Some(format!("timed out after {:?}", timeout))),
+    };
+
+    ComponentHealth { name: name.to_string(), status, critical, latency_ms, details, error }
+}
+
+
+async fn check_postgres(pool: &PgPool) -> Result<Value, String> {
+    db_health_check_pgsql(pool).await.map_err(|e| e.to_string())?;
+
+    let status = pool.status();
+    Ok(json!({
+        "max_size": status.max_size,
+        "size": status.size,
+        "available": status.available,
+        "waiting": status.waiting,
+        "breaker": breaker::of(pool).map(|b| b.state().as_str()),
+    }))
+}
+
+
+async fn check_cache(cache: &AppCache) -> Result<Value, String> {
+    const CACHE_KEY: &str = "health_check";
+    const CACHE_VALUE: &str = "Cache is running!";
+
+    cache.insert(make_key(CACHE_KEY), CACHE_VALUE.to_string()).await;
+    match cache.get(&make_key(CACHE_KEY)).await {
+        Some(value) if value == CACHE_VALUE => Ok(json!({
+            "entry_count": cache.entry_count(),
+            "weighted_size": cache.weighted_size(),
+        })),
+        _ => Err("cache did not return the value it stored".to_string()),
+    }
+}
+
+
+async fn check_jobs(jobs: &JobQueue) -> Result<Value, String> {
+    jobs.health().await
+}
+
+
+// ------- Implementations ------- //
+
+
+impl HealthChecker {
+    pub fn new(check_timeout: Duration, cache_ttl: Duration) -> Self {
+        HealthChecker { check_timeout, cache_ttl, last: Mutex::new(None) }
+    }
+
+    /// Check every component (concurrently), or return the cached report if it is fresh enough
+    pub async fn report(&self, db: &ReplicaSet, cache: &AppCache, jobs: &JobQueue) -> HealthReport {
+        // Holding the lock while checking coalesces concurrent probes into one round of checks
+        let mut last = self.last.lock().await;
+        if let Some((at, report)) = last.as_ref()
+            && at.elapsed() < self.cache_ttl
+        {
+            return HealthReport { cached: true, ..report.clone() };
+        }
+
+        let timeout = self.check_timeout;
+        // Reads fall back to the primary, so replicas are never critical
+        let replicas = async {
+            if !db.has_replicas() {
+                return None;
+            }
+            Some(run_check("postgres_replicas", false, timeout, db.health(timeout)).await)
+        };
+        let (postgres, cache, jobs, replicas) = tokio::join!(
+            run_check("postgres", true, timeout, check_postgres(db.primary())),
+            run_check("cache", true, timeout, check_cache(cache)),
+            run_check("jobs", false, timeout, check_jobs(jobs)),
+            replicas,
+        );
+        let mut components = vec![postgres, cache, jobs];
+        components.extend(replicas);
+        let report = HealthReport::from_components(components);
+
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+
+impl HealthReport {
+    /// Down if a critical component is down, degraded if any other one is
+    fn from_components(components: Vec<ComponentHealth>) -> Self {
+        let status = if components.iter().any(|c| c.critical && c.status == Status::Down) {
+            Status::Down
+        } else if components.iter().any(|c| c.status != Status::Up) {
+            Status::Degraded
+        } else {
+            Status::Up
+        };
+
+        HealthReport { status, cached: false, components }
+    }
+}
@@ -1 +1,3 @@
 // Handler functions for various routes (if the endpoint has complex logic, it should move here)
+
+pub mod health;
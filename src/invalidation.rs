@@ -0,0 +1,178 @@
+use tokio_postgres::{AsyncMessage, Config, Notification};
+use crate::database::{query::Query, tls::MakeRustlsConnect, transaction::Executor, DbError};
+use serde::{Deserialize, Serialize};
+use std::{future::poll_fn, sync::{atomic::{AtomicBool, Ordering}, OnceLock}, time::Duration};
+use crate::utils::AppCache;
+use crate::metrics;
+use log::{debug, info, warn};
+
+
+// Backoff between two connection attempts of the listener, doubled after every failure
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+// A silent connection is checked this often, a dead one would otherwise miss every notification
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Channel the evictions are published on, unset when the invalidation is disabled
+static CHANNEL: OnceLock<String> = OnceLock::new();
+
+// Set by `spawn_listener`, then whether the listener is connected
+static LISTENER: OnceLock<AtomicBool> = OnceLock::new();
+
+const NOTIFY: Query = Query {
+    name: "notify_eviction",
+    sql: "SELECT pg_notify($1, $2)",
+    columns: &[],
+    timeout: None,
+};
+
+
+/// Entries to evict from the cache of every instance, sent as the JSON payload of a notification
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    Key(String),
+    Namespace(String),  // Every key starting with `<namespace>:`
+}
+
+
+
+/// True if `channel` can be used unquoted in `LISTEN` (a lowercase Postgres identifier)
+pub fn is_valid_channel(channel: &str) -> bool {
+    channel.len() < 64
+        && channel.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && channel.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+
+/// Publish the evictions on `channel` (the first one wins), without listening in this process
+pub fn set_channel(channel: &str) {
+    let _ = CHANNEL.set(channel.to_string());
+}
+
+
+/// False while the listener of this process is not listening yet (or reconnecting), true when there is none
+pub fn is_listening() -> bool {
+    LISTENER.get().is_none_or(|listening| listening.load(Ordering::Relaxed))
+}
+
+
+/// Key of an entry in the namespace
+pub fn namespaced(namespace: &str, key: &str) -> String {
+    format!("{}:{}", namespace, key)
+}
+
+
+/// Publish an eviction to every instance (this one included). Sent through `db`, inside a transaction it is only
+/// delivered once the transaction commits. Nothing is sent when the invalidation is disabled.
+pub async fn publish(db: &impl Executor, eviction: &Eviction) -> Result<(), DbError> {
+    let Some(channel) = CHANNEL.get() else { return Ok(()) };
+    let payload = serde_json::to_string(eviction).unwrap();
+
+    let client = db.conn().await?;
+    client.query_one(&NOTIFY, &[channel, &payload]).await?;
+    Ok(())
+}
+
+
+/// Evict what a notification names from the local cache
+async fn apply(cache: &AppCache, notification: &Notification) {
+    let eviction = match serde_json::from_str::<Eviction>(notification.payload()) {
+        Ok(eviction) => eviction,
+        Err(e) => {
+            warn!("Ignoring a malformed cache invalidation '{}': {}", notification.payload(), e);
+            return;
+        }
+    };
+
+    debug!("Cache invalidation from backend {}: {:?}", notification.process_id(), eviction);
+    let kind = match eviction {
+        Eviction::Key(key) => {
+            cache.invalidate(key.as_str()).await;
+            "key"
+        }
+        Eviction::Namespace(namespace) => {
+            let prefix = namespaced(&namespace, "");
+            if let Err(e) = cache.invalidate_entries_if(move |key, _| key.starts_with(&prefix)) {
+                warn!("Could not evict the cache namespace '{}': {}", namespace, e);
+            }
+            "namespace"
+        }
+    };
+    metrics::get().cache_invalidations.with_label_values(&[kind]).inc();
+}
+
+
+/// Listen on one connection until it fails or closes, evictions are applied as they arrive
+async fn listen(config: &Config, tls: MakeRustlsConnect, channel: &str, cache: &AppCache) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = config.connect(tls).await?;
+
+    // The connection only makes progress (notifications included) while it is polled
+    let cache = cache.clone();
+    let mut messages = tokio::spawn(async move {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+            if let AsyncMessage::Notification(notification) = message? {
+                apply(&cache, &notification).await;
+            }
+        }
+        Ok(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", channel)).await?;
+    info!("Listening for cache invalidations on channel '{}'", channel);
+    metrics::get().cache_invalidation_listener.set(1);
+    LISTENER.get_or_init(Default::default).store(true, Ordering::Relaxed);
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+    let result = loop {
+        tokio::select! {
+            ended = &mut messages => break ended.unwrap_or(Ok(())),
+            _ = ping.tick() => match tokio::time::timeout(PING_TIMEOUT, client.simple_query("SELECT 1")).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => break Err(e),
+                Err(_) => {
+                    warn!("Cache invalidation connection did not answer within {:?}, reconnecting", PING_TIMEOUT);
+                    break Ok(());
+                }
+            },
+        }
+    };
+
+    messages.abort();
+    metrics::get().cache_invalidation_listener.set(0);
+    LISTENER.get_or_init(Default::default).store(false, Ordering::Relaxed);
+    result
+}
+
+
+/// Enable the invalidation on `channel` and keep a dedicated connection listening on it in the background,
+/// reconnecting with backoff. Evictions published while it is disconnected are missed, the TTL bounds the staleness.
+pub fn spawn_listener(config: Config, tls: MakeRustlsConnect, channel: String, cache: AppCache) {
+    set_channel(&channel);
+    LISTENER.get_or_init(Default::default);
+
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_MIN;
+        loop {
+            let started = tokio::time::Instant::now();
+            match listen(&config, tls.clone(), &channel, &cache).await {
+                Ok(()) => warn!("Cache invalidation connection closed"),
+                Err(e) => match e.as_db_error() {
+                    Some(db) => warn!("Cache invalidation connection failed: {}", db.message()),
+                    None => warn!("Cache invalidation connection failed: {}", e),
+                },
+            }
+
+            // A connection that lived a while starts the backoff over
+            if started.elapsed() > RECONNECT_MAX {
+                delay = RECONNECT_MIN;
+            }
+            warn!("Reconnecting the cache invalidation listener in {:?}, evictions are missed meanwhile", delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX);
+        }
+    });
+}
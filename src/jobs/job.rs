@@ -0,0 +1,98 @@
+use crate::database::{notes::add_new_notes, retry::is_transient, DbError};
+use deadpool_postgres::{Pool as PgPool, Transaction};
+use serde::{Deserialize, Serialize};
+use crate::models::notes::CreateNote;
+use std::{fmt, time::SystemTime};
+use serde_json::{json, Value};
+
+
+/// Work run in the background by the job workers, one variant per kind of job.
+/// Stored as its kind and a JSON payload, so a variant must stay readable by the next release.
+/// Its writes commit with the job being marked succeeded, so a job retried after a lost claim or a crash never
+/// applies them twice.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Job {
+    /// Insert the notes of a create request, all or none
+    InsertNotes(Vec<CreateNote>),
+}
+
+
+/// How a job is queued, the default runs it as soon as a worker is free
+#[derive(Default)]
+pub struct JobOptions {
+    pub priority: i32,                  // Higher runs first
+    pub run_at: Option<SystemTime>,     // Not before this time
+    pub dedupe_key: Option<String>,     // Not queued while a job with the same key is queued or running
+    pub max_attempts: Option<u32>,      // Instead of `JOBS_MAX_ATTEMPTS`
+}
+
+
+/// What the jobs run with
+pub struct JobContext {
+    pub db: PgPool,
+}
+
+
+/// Why a job failed, a retryable failure is run again after a backoff (until the attempts run out)
+#[derive(Debug)]
+pub enum JobError {
+    Retryable(String),
+    Permanent(String),
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Job {
+    /// Name of the kind of job, stored with it and labels its logs and metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::InsertNotes(_) => "insert_notes",
+        }
+    }
+
+    /// The stored payload of the job
+    pub fn payload(&self) -> Value {
+        let mut tagged = serde_json::to_value(self).expect("jobs serialize to JSON");
+        tagged["payload"].take()
+    }
+
+    /// Read a stored job back, fails for an unknown kind or a payload that does not match it
+    pub fn decode(kind: &str, payload: Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(json!({ "kind": kind, "payload": payload }))
+    }
+
+    /// Do the work of the job in `tx`, the transaction marking it succeeded
+    pub async fn run(&self, tx: &Transaction<'_>) -> Result<(), DbError> {
+        match self {
+            Job::InsertNotes(notes) => {
+                let inserted = add_new_notes(tx, notes).await?;
+                log::debug!("Inserted {} notes", inserted);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Retryable(e) | JobError::Permanent(e) => f.write_str(e),
+        }
+    }
+}
+
+
+impl From<DbError> for JobError {
+    fn from(e: DbError) -> Self {
+        // Postgres being down or restarting is worth waiting for, a rejected statement fails again
+        if matches!(e, DbError::Unavailable(_)) || is_transient(&e) {
+            JobError::Retryable(e.to_string())
+        } else {
+            JobError::Permanent(e.to_string())
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::database::jobs::{bury_job, claim_jobs, complete_job, count_jobs, insert_job, purge_jobs, reclaim_expired_jobs, release_jobs, reschedule_job};
+use crate::database::{retry::backoff, transaction::{with_transaction, TxOptions}, DbError};
+use crate::{metrics, models::{initial::JobSettings, jobs::{ClaimedRow, Inserted, NewJob}}};
+use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use std::time::{Duration, Instant, SystemTime};
+use job::{Job, JobContext, JobError, JobOptions};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+use deadpool_postgres::Pool as PgPool;
+use serde_json::{json, Value};
+use tracing::Instrument;
+use log::{debug, error, info, warn};
+use uuid::Uuid;
+
+pub mod job;
+
+
+// Backoff between two attempts of a job never goes above it
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+// How often a worker process re-queues the expired claims, purges the old jobs and samples the queue depth
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(15);
+
+
+/// Handle to enqueue jobs, shared as app data. The jobs are stored in the `jobs` table and run by the workers of
+/// every process polling it (this one included when it runs workers).
+pub struct JobQueue {
+    db: PgPool,
+    max_attempts: u32,
+    max_queued: i64,
+    wake: Arc<Notify>,
+    closing: CancellationToken,
+    dispatcher: Mutex<Option<JoinHandle<()>>>,
+    workers: Option<Arc<Workers>>,
+}
+
+
+/// Why a job was not queued
+#[derive(Debug)]
+pub enum EnqueueError {
+    Full,       // `JOBS_MAX_QUEUED` jobs are waiting
+    Conflict,   // The dedupe key kept conflicting, see `insert_job`
+    Db(DbError),
+}
+
+
+/// Runs the claimed jobs with at most `concurrency` at a time, each attempt limited by the timeout
+struct Workers {
+    id: String,     // Owner of the claims of this process
+    ctx: JobContext,
+    slots: Arc<Semaphore>,
+    concurrency: usize,
+    timeout: Duration,
+    visibility_timeout: Duration,
+    poll_interval: Duration,
+    backoff: Duration,
+    retention: Duration,
+    processed: AtomicU64,
+    claimed_once: AtomicBool,   // The jobs table answered a claim
+}
+
+
+
+/// Claim jobs as slots free up: right away when a job of this process is enqueued or one finishes, every poll
+/// interval otherwise. Once closing it stops claiming and waits for the running jobs.
+async fn dispatch(workers: Arc<Workers>, wake: Arc<Notify>, closing: CancellationToken) {
+    let mut running = JoinSet::new();
+    let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+    while !closing.is_cancelled() {
+        while running.try_join_next().is_some() {}
+
+        let free = workers.slots.available_permits();
+        if free > 0 {
+            match claim_jobs(&workers.ctx.db, &workers.id, free, workers.visibility_timeout).await {
+                Ok(claimed) => {
+                    workers.claimed_once.store(true, Ordering::Relaxed);
+                    let full_batch = claimed.len() == free;
+                    for job in claimed {
+                        let slot = workers.slots.clone().try_acquire_owned().expect("a slot is free for every claimed job");
+                        running.spawn(workers.clone().run(job, slot));
+                    }
+                    // More jobs may be ready
+                    if full_batch {
+                        continue;
+                    }
+                }
+                Err(e) => warn!("Could not claim jobs: {}", e),
+            }
+        }
+
+        tokio::select! {
+            _ = closing.cancelled() => {}
+            _ = maintenance.tick() => workers.maintain().await,
+            _ = wake.notified() => {}
+            _ = tokio::time::sleep(workers.poll_interval) => {}
+            Some(_) = running.join_next(), if !running.is_empty() => {}
+        }
+    }
+
+    while running.join_next().await.is_some() {}
+}
+
+
+// ------- Implementations ------- //
+
+
+impl JobQueue {
+    /// Enqueue only, the jobs are run by other processes (`worker` subcommand)
+    pub fn new(settings: &JobSettings, db: PgPool) -> Self {
+        JobQueue {
+            db,
+            max_attempts: settings.max_attempts,
+            max_queued: settings.max_queued.into(),
+            wake: Arc::new(Notify::new()),
+            closing: CancellationToken::new(),
+            dispatcher: Mutex::new(None),
+            workers: None,
+        }
+    }
+
+    /// Enqueue and run jobs, the workers live until `shutdown`
+    pub fn start(settings: &JobSettings, ctx: JobContext) -> Self {
+        let mut queue = JobQueue::new(settings, ctx.db.clone());
+        let workers = Arc::new(Workers {
+            id: format!("{}-{}", std::process::id(), Uuid::new_v4().simple()),
+            ctx,
+            slots: Arc::new(Semaphore::new(settings.concurrency)),
+            concurrency: settings.concurrency,
+            timeout: settings.timeout,
+            visibility_timeout: settings.visibility_timeout,
+            poll_interval: settings.poll_interval,
+            backoff: settings.backoff,
+            retention: settings.retention,
+            processed: AtomicU64::new(0),
+            claimed_once: AtomicBool::new(false),
+        });
+        let dispatcher = tokio::spawn(dispatch(workers.clone(), queue.wake.clone(), queue.closing.clone()));
+
+        info!(
+            "Job workers {} started (concurrency={}, max_attempts={}, visibility_timeout={:?})",
+            workers.id, settings.concurrency, settings.max_attempts, settings.visibility_timeout,
+        );
+        queue.dispatcher = Mutex::new(Some(dispatcher));
+        queue.workers = Some(workers);
+        queue
+    }
+
+    /// Queue a job to run as soon as a worker is free, returns its ID
+    pub async fn enqueue(&self, job: Job) -> Result<Uuid, EnqueueError> {
+        self.enqueue_with(job, JobOptions::default()).await
+    }
+
+    /// Queue a job, returns its ID (the ID of the waiting job when deduplicated). Fails while too many jobs are queued.
+    pub async fn enqueue_with(&self, job: Job, options: JobOptions) -> Result<Uuid, EnqueueError> {
+        let payload = job.payload();
+        let new = NewJob {
+            kind: job.kind(),
+            payload: &payload,
+            priority: options.priority,
+            run_at: options.run_at.unwrap_or_else(SystemTime::now),
+            dedupe_key: options.dedupe_key.as_deref(),
+            max_attempts: options.max_attempts.unwrap_or(self.max_attempts).max(1) as i32,
+        };
+
+        match insert_job(&self.db, Uuid::new_v4(), &new, self.max_queued).await.map_err(EnqueueError::Db)? {
+            Inserted::Created(id) => {
+                debug!("Job {} ({}) queued", id, new.kind);
+                if new.run_at <= SystemTime::now() {
+                    self.wake.notify_one();
+                }
+                Ok(id)
+            }
+            Inserted::Duplicate(id) => {
+                debug!("Job {} ({}) not queued, job {} has the same dedupe key", new.kind, new.dedupe_key.unwrap_or_default(), id);
+                metrics::get().jobs_deduplicated.inc();
+                Ok(id)
+            }
+            Inserted::Full => {
+                warn!("Job {} not queued, {} jobs are waiting already", new.kind, self.max_queued);
+                metrics::get().jobs_rejected.inc();
+                Err(EnqueueError::Full)
+            }
+            Inserted::Conflict => {
+                warn!("Job {} not queued, its dedupe key {} kept conflicting", new.kind, new.dedupe_key.unwrap_or_default());
+                Err(EnqueueError::Conflict)
+            }
+        }
+    }
+
+    /// Jobs in the table and the workers of this process, an error once its dispatcher stopped
+    pub async fn health(&self) -> Result<Value, String> {
+        if self.workers.is_some() && self.dispatcher.lock().unwrap().as_ref().is_none_or(|h| h.is_finished()) {
+            return Err("job dispatcher is not running".to_string());
+        }
+
+        let counts = count_jobs(&self.db).await.map_err(|e| e.to_string())?;
+        Ok(match &self.workers {
+            Some(workers) => json!({
+                "jobs": counts,
+                "workers": workers.id,
+                "running": workers.concurrency - workers.slots.available_permits(),
+                "processed": workers.processed.load(Ordering::Relaxed),
+            }),
+            None => json!({ "jobs": counts, "workers": "off" }),
+        })
+    }
+
+    /// True once the workers of this process claimed from the table (or there are none), the startup waits for it
+    pub fn is_started(&self) -> bool {
+        self.workers.as_ref().is_none_or(|workers| workers.claimed_once.load(Ordering::Relaxed))
+    }
+
+    /// Jobs the workers of this process finished (succeeded or dead)
+    pub fn processed(&self) -> u64 {
+        self.workers.as_ref().map_or(0, |workers| workers.processed.load(Ordering::Relaxed))
+    }
+
+    /// Stop claiming jobs and finish the running ones. Past `timeout` they are cancelled and queued again
+    /// for another worker, returns how many.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        self.closing.cancel();
+        let Some(mut dispatcher) = self.dispatcher.lock().unwrap().take() else { return 0 };
+        let Some(workers) = &self.workers else { return 0 };
+
+        if tokio::time::timeout(timeout, &mut dispatcher).await.is_ok() {
+            return 0;
+        }
+
+        // Dropping the dispatcher drops its running jobs
+        let abandoned = workers.concurrency - workers.slots.available_permits();
+        dispatcher.abort();
+        let _ = dispatcher.await;
+        match release_jobs(&self.db, &workers.id).await {
+            Ok(released) => warn!("Job workers did not finish in time, {} running jobs queued again", released),
+            Err(e) => warn!(
+                "Job workers did not finish in time, {} running jobs run again once their visibility timeout expires: {}",
+                abandoned, e,
+            ),
+        }
+        abandoned
+    }
+}
+
+
+impl Workers {
+    /// Run one attempt of a claimed job and record its outcome: succeeded, queued again after a backoff, or dead
+    async fn run(self: Arc<Self>, mut claimed: ClaimedRow, _slot: OwnedSemaphorePermit) {
+        let kind = std::mem::take(&mut claimed.kind);
+        let (id, attempt, max_attempts) = (claimed.id, claimed.attempts, claimed.max_attempts);
+
+        let result = match Job::decode(&kind, claimed.payload.take()) {
+            Ok(job) => {
+                let span = tracing::info_span!("job.run", job.id = %id, job.kind = %kind, job.attempt = attempt);
+                let started = Instant::now();
+                // Dropped on a timeout, which rolls the work back
+                let result = match tokio::time::timeout(self.timeout, self.attempt(job, &claimed)).instrument(span).await {
+                    Ok(result) => result.map_err(JobError::from),
+                    Err(_) => Err(JobError::Retryable(format!("timed out after {:?}", self.timeout))),
+                };
+                metrics::get().jobs_duration.with_label_values(&[&kind]).observe(started.elapsed().as_secs_f64());
+                result
+            }
+            // Unknown kind (e.g. queued by a newer release) or payload no longer matching its kind
+            Err(e) => Err(JobError::Permanent(format!("can not decode the job: {}", e))),
+        };
+
+        let db = &self.ctx.db;
+        let (outcome, recorded) = match result {
+            // Marked succeeded with its work
+            Ok(true) => {
+                debug!("Job {} ({}) succeeded, attempt {}", id, kind, attempt);
+                ("succeeded", Ok(true))
+            }
+            Ok(false) => {
+                warn!("Job {} ({}) lost its claim before it ran (visibility timeout expired), it is left to the other worker", id, kind);
+                return;
+            }
+            Err(JobError::Retryable(e)) if attempt < max_attempts => {
+                let delay = backoff(self.backoff, BACKOFF_MAX, attempt as u32);
+                warn!("Job {} ({}) failed, attempt {}/{}, retrying in {:?}: {}", id, kind, attempt, max_attempts, delay, e);
+                ("retried", reschedule_job(db, &claimed, &self.id, delay, &e).await)
+            }
+            Err(e) => {
+                error!("Job {} ({}) failed for good after {} attempts: {}", id, kind, attempt, e);
+                ("dead", bury_job(db, &claimed, &self.id, &e.to_string()).await)
+            }
+        };
+
+        match recorded {
+            Ok(true) => {}
+            Ok(false) => warn!("Job {} ({}) lost its claim while running (visibility timeout expired), its outcome is dropped", id, kind),
+            Err(e) => warn!("Could not record the outcome of job {} ({}), it runs again once its visibility timeout expires: {}", id, kind, e),
+        }
+        metrics::get().jobs_processed.with_label_values(&[&kind, outcome]).inc();
+        if outcome != "retried" {
+            self.processed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Mark the job succeeded and run it in the same transaction, false if the claim was lost (nothing ran then).
+    /// Marked first so the row stays locked until the work commits with it, and no other worker can reclaim it meanwhile.
+    async fn attempt(&self, job: Job, claimed: &ClaimedRow) -> Result<bool, DbError> {
+        // Owned, a borrow captured by the closure would make the spawned job future not `Send`
+        let (claimed, worker) = (claimed.clone(), self.id.clone());
+        with_transaction(&self.ctx.db, TxOptions::default(), async move |tx| {
+            if !complete_job(tx, &claimed, &worker).await? {
+                return Ok(false);
+            }
+            job.run(tx).await?;
+            Ok(true)
+        }).await
+    }
+
+    /// Queue again the claims of stopped workers, purge the old finished jobs and sample the queue depth
+    async fn maintain(&self) {
+        let db = &self.ctx.db;
+        match reclaim_expired_jobs(db).await {
+            Ok((0, 0)) => {}
+            Ok((queued, dead)) => warn!("{} jobs queued again and {} dead after their visibility timeout expired", queued, dead),
+            Err(e) => warn!("Could not reclaim the expired jobs: {}", e),
+        }
+
+        if !self.retention.is_zero() {
+            match purge_jobs(db, self.retention).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} finished jobs older than {:?}", purged, self.retention),
+                Err(e) => warn!("Could not purge the finished jobs: {}", e),
+            }
+        }
+
+        match count_jobs(db).await {
+            Ok(counts) => metrics::get().jobs_queued.set(counts.ready + counts.scheduled),
+            Err(e) => debug!("Could not count the queued jobs: {}", e),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use log::{kv::{Key, Value, VisitSource}, Log, Metadata, Record};
+use std::{cell::RefCell, future::Future, io::Write, str::FromStr};
+use std::sync::{OnceLock, RwLock};
+use serde_json::{Map, Value as Json};
+use crate::telemetry;
+
+
+/// Layout of a log line (`APP_LOG_FORMAT`)
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+    Logfmt,
+}
+
+
+/// env_logger wrapper whose filter and format can be swapped at runtime (config reload)
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+
+/// Request scoped fields added to every line logged while the request is handled
+struct RequestContext {
+    id: String,
+    user: RefCell<Option<String>>,
+}
+
+
+/// Collects the key-values of a record (`info!(status = 200; "...")`)
+struct Fields(Vec<(String, Json)>);
+
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+
+
+fn build_logger(filter: &str, format: LogFormat) -> env_logger::Logger {
+    env_logger::Builder::new()
+        .parse_filters(filter)
+        .format(move |buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            let line = format_record(format, &timestamp, record);
+            writeln!(buf, "{}", line)
+        })
+        .build()
+}
+
+
+/// Install the logger with the given filter (`RUST_LOG` syntax), does nothing if already installed
+pub fn init(filter: &str, format: LogFormat) {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger { inner: RwLock::new(build_logger(filter, format)) });
+
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.inner.read().unwrap().filter());
+    }
+}
+
+
+/// Replace the filter and format of the installed logger (no-op when logging is disabled)
+pub fn reconfigure(filter: &str, format: LogFormat) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    let new_logger = build_logger(filter, format);
+    log::set_max_level(new_logger.filter());
+    *logger.inner.write().unwrap() = new_logger;
+}
+
+
+/// Run a request future with its id attached to every line it logs
+pub async fn with_request<F>(id: String, request: F) -> F::Output
+    where F: Future
+{
+    REQUEST.scope(RequestContext { id, user: RefCell::new(None) }, request).await
+}
+
+
+/// Attach the authenticated user to the lines logged for the rest of the current request
+pub fn set_user(user: &str) {
+    let _ = REQUEST.try_with(|ctx| *ctx.user.borrow_mut() = Some(user.to_string()));
+}
+
+
+/// Id of the request being handled, if any
+pub fn request_id() -> Option<String> {
+    REQUEST.try_with(|ctx| ctx.id.clone()).ok()
+}
+
+
+/// Request id, user, trace id and the record's own key-values
+fn record_fields(record: &Record) -> Vec<(String, Json)> {
+    let mut fields = Fields(Vec::new());
+
+    let _ = REQUEST.try_with(|ctx| {
+        fields.0.push(("request_id".to_string(), Json::from(ctx.id.as_str())));
+        if let Some(user) = ctx.user.borrow().as_deref() {
+            fields.0.push(("user".to_string(), Json::from(user)));
+        }
+    });
+    if let Some(trace_id) = telemetry::current_trace_id() {
+        fields.0.push(("trace_id".to_string(), Json::from(trace_id)));
+    }
+    let _ = record.key_values().visit(&mut fields);
+
+    fields.0
+}
+
+
+fn format_record(format: LogFormat, timestamp: &str, record: &Record) -> String {
+    let fields = record_fields(record);
+
+    match format {
+        LogFormat::Json => {
+            let mut line = Map::new();
+            line.insert("timestamp".to_string(), Json::from(timestamp));
+            line.insert("level".to_string(), Json::from(record.level().as_str()));
+            line.insert("target".to_string(), Json::from(record.target()));
+            line.insert("message".to_string(), Json::from(record.args().to_string()));
+            line.extend(fields);
+            Json::Object(line).to_string()
+        }
+        LogFormat::Logfmt => {
+            let mut line = format!(
+                "ts={} level={} target={} msg={}",
+                timestamp,
+                record.level().as_str().to_lowercase(),
+                record.target(),
+                logfmt_value(&Json::from(record.args().to_string())),
+            );
+            for (key, value) in &fields {
+                line.push_str(&format!(" {}={}", key, logfmt_value(value)));
+            }
+            line
+        }
+        LogFormat::Text => {
+            let mut line = format!("[{} {:<5} {}] {}", timestamp, record.level(), record.target(), record.args());
+            for (key, value) in &fields {
+                line.push_str(&format!(" {}={}", key, logfmt_value(value)));
+            }
+            line
+        }
+    }
+}
+
+
+/// Render a value for logfmt, quoting it when it contains spaces, quotes or `=`
+fn logfmt_value(value: &Json) -> String {
+    let text = match value {
+        Json::String(s) => s.clone(),
+        Json::Null => return String::new(),
+        other => other.to_string(),
+    };
+
+    if text.is_empty() || text.contains([' ', '"', '=', '\n']) {
+        format!("{:?}", text)
+    } else {
+        text
+    }
+}
+
+
+// ------- Implementations ------- //
+
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            other => Err(format!("unknown log format '{}', expected text, json or logfmt", other)),
+        }
+    }
+}
+
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| Json::from(value.to_string()));
+        if !value.is_null() {
+            self.0.push((key.to_string(), value));
+        }
+        Ok(())
+    }
+}
+
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let inner = self.inner.read().unwrap();
+        if inner.matches(record) {
+            inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush();
+    }
+}
@@ -1,59 +1,177 @@
-use actix_web::web::scope as actix_scope;
-use routes::{health, sample_db, auth};
+use actix_web::{http::KeepAlive, web, App, HttpServer};
 use actix_web::middleware::from_fn;
-use actix_web::{App, HttpServer};
-use std::env::var as env_var;
-use actix_cors::Cors;
+use models::initial::AppSettings;
+use std::time::Duration;
 
 mod middleware;
+mod handlers;
 mod database;
 mod models;
 mod routes;
+mod logging;
+mod metrics;
+mod telemetry;
+mod rate_limit;
+mod invalidation;
+mod jobs;
+mod config;
+mod shutdown;
+mod reload;
 mod state;
 mod utils;
 
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let (pg_pool, in_mem_cache, tx) = state::initialize().await;
+    // Layered configuration: defaults, config file, env vars, then CLI flags
+    let cli = config::CliArgs::from_env_args();
+    if cli.help_config {
+        print!("{}", config::help_text());
+        return Ok(());
+    }
+    if cli.config_schema {
+        println!("{:#}", config::json_schema());
+        return Ok(());
+    }
 
-    // Start the Actix web server
-    HttpServer::new(move || {
+    let raw_config = config::RawConfig::load(&cli);
+    if cli.print_config {
+        print!("{}", raw_config.render_redacted());
+    }
+
+    let app_settings = match AppSettings::from_config(&raw_config) {
+        Ok(settings) => settings,
+        Err(report) => {
+            eprint!("{}", report);
+            std::process::exit(2);
+        }
+    };
+    if cli.print_config {
+        return Ok(());
+    }
+
+    // A worker process only runs the background jobs, it stops on SIGINT or SIGTERM after finishing them
+    if cli.worker {
+        let (pg_pool, jobs) = state::initialize_worker(&app_settings).await;
+        for warning in raw_config.warnings() {
+            log::warn!("{}", warning);
+        }
+        shutdown::run_worker(pg_pool, jobs, app_settings.server_settings.shutdown_timeout).await;
+        return Ok(());
+    }
+
+    let app_state = state::initialize(&app_settings).await;
+    for warning in raw_config.warnings() {
+        log::warn!("{}", warning);
+    }
+
+    let server_settings = &app_settings.server_settings;
+    let (json_limit, payload_limit) = (server_settings.json_limit, server_settings.payload_limit);
+    let (pg_pool, in_mem_cache, jobs, api_settings, lifecycle, health) = (
+        app_state.pg_pool.clone(),
+        app_state.cache.clone(),
+        app_state.jobs.clone(),
+        app_state.api_settings.clone(),
+        app_state.lifecycle.clone(),
+        app_state.health.clone(),
+    );
+    let rate_limiter = app_state.rate_limiter.clone();
+    let replicas = app_state.replicas.clone();
+    let (cors_settings, cors_origins) = (app_settings.cors_settings.clone(), app_state.cors_origins.clone());
+    let security_headers = web::Data::new(middleware::security_headers::SecurityHeaders::new(&app_settings.security_headers_settings));
+    let access_log = web::Data::new(middleware::access_log::AccessLog { log_headers: app_settings.access_log_headers });
+    let request_timeout = app_settings.pg_settings.request_timeout;
+    let query_deadline = web::Data::new(middleware::deadline::QueryDeadline { timeout: (!request_timeout.is_zero()).then_some(request_timeout) });
+
+    // Build the Actix web server
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(pg_pool.clone())
             .app_data(in_mem_cache.clone())
-            .app_data(tx.clone())
-            .wrap(Cors::default()
-                .allow_any_origin()
-                .allowed_methods(vec!["GET", "POST", "DELETE"])
-                .allow_any_header()
-                .max_age(60)
-            )
-            .service(
-                actix_scope("/health")
-                .service(health::api_health_check)
-                .service(health::db_health_check)
-                .service(health::cache_health_check)
-                .service(health::channel_health_check)
-            )
-            .service(
-                actix_scope("/sample_db")
-                .wrap(from_fn(middleware::auth::auth_check))
-                .service(sample_db::create_note_handler)
-                .service(sample_db::list_notes_handler)
-            )
-            .service(
-                actix_scope("/auth")
-                .service(auth::create_session_handler)
-                .service(
-                    actix_scope("")
-                    .wrap(from_fn(middleware::auth::auth_check))
-                    .service(auth::delete_session_handler)
-                    .service(auth::get_session_handler)
-                )
-            )
+            .app_data(jobs.clone())
+            .app_data(api_settings.clone())
+            .app_data(lifecycle.clone())
+            .app_data(health.clone())
+            .app_data(access_log.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(replicas.clone())
+            .app_data(security_headers.clone())
+            .app_data(query_deadline.clone())
+            .app_data(web::JsonConfig::default().limit(json_limit))
+            .app_data(web::PayloadConfig::new(payload_limit))
+            .wrap(from_fn(middleware::deadline::set_query_deadline))
+            .wrap(from_fn(middleware::versioning::negotiate_version))
+            .wrap(from_fn(middleware::rate_limit::limit_default))
+            .wrap(from_fn(middleware::shutdown::track_requests))
+            .wrap(middleware::cors::build(&cors_settings, cors_origins.clone()))
+            .wrap(from_fn(middleware::security_headers::set_security_headers))
+            .wrap(from_fn(middleware::metrics::record_metrics))
+            .wrap(from_fn(middleware::trace::trace_requests))
+            .wrap(from_fn(middleware::access_log::log_access))
+            .wrap(from_fn(middleware::request_id::assign_request_id))
+            .configure(routes::configure)
     })
-    .bind(("0.0.0.0", 8686))?
-    .workers(env_var("API_WORKERS_COUNT").unwrap_or("4".to_string()).parse().unwrap())
-    .run().await
+    .workers(server_settings.workers)
+    .backlog(server_settings.backlog)
+    .max_connections(server_settings.max_connections)
+    .keep_alive(match server_settings.keep_alive {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    })
+    .client_request_timeout(Duration::from_millis(server_settings.client_request_timeout))
+    .client_disconnect_timeout(Duration::from_millis(server_settings.client_disconnect_timeout))
+    .h1_allow_half_closed(false) // A client going away drops its handler, which cancels the running queries
+    .shutdown_timeout(server_settings.shutdown_timeout.as_secs())
+    .disable_signals(); // Signals are handled by `shutdown::handle_signals` to drain in order
+
+    // Bind all the listeners (TLS, h2c or plain HTTP/1.1)
+    let (tls_config, cert_resolver) = match &server_settings.tls {
+        Some(tls) => {
+            let (config, resolver) = state::load_tls_config(tls, server_settings.http2)?;
+            (Some(config), Some(resolver))
+        }
+        None => (None, None),
+    };
+    for ip in &server_settings.listen_addresses {
+        let addr = (*ip, server_settings.port);
+        server = match &tls_config {
+            Some(tls_config) => server.bind_rustls_0_23(addr, tls_config.clone())?,
+            None if server_settings.http2 => server.bind_auto_h2c(addr)?,
+            None => server.bind(addr)?,
+        };
+        log::info!("Listening on {}://{}:{}", if tls_config.is_some() { "https" } else { "http" }, ip, server_settings.port);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &server_settings.unix_socket {
+        // Remove a stale socket left by a previous run, never a regular file
+        use std::os::unix::fs::FileTypeExt;
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        server = server.bind_uds(path)?;
+        log::info!("Listening on unix socket {}", path.display());
+    }
+
+    // Reload the runtime settings on SIGHUP or when the config / TLS files change
+    let reload_interval = app_settings.reload_interval;
+    reload::Reloader::new(
+        cli,
+        raw_config,
+        &app_settings,
+        app_state.cache_ttl.clone(),
+        cert_resolver,
+        app_state.rate_limiter.clone(),
+        app_state.cors_origins.clone(),
+    )
+    .spawn(reload_interval);
+
+    // Run until a shutdown signal, then drain and clean up
+    let server = server.run();
+    shutdown::spawn_startup(&app_state);
+    let (shutdown_delay, shutdown_timeout) = (server_settings.shutdown_delay, server_settings.shutdown_timeout);
+    shutdown::handle_signals(server.handle(), app_state.lifecycle.clone(), shutdown_delay, shutdown_timeout);
+    server.await?;
+
+    shutdown::finish(app_state, shutdown_timeout).await;
+    Ok(())
 }
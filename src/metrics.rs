@@ -0,0 +1,179 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use crate::database::replicas::ReplicaSet;
+use std::sync::LazyLock;
+use crate::utils::AppCache;
+
+
+/// Every metric the server exports on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,       // method, route, status
+    pub http_duration: HistogramVec,        // method, route, status
+    pg_pool: IntGaugeVec,                   // pool, state: max_size, size, available, waiting
+    pub pg_checkout_duration: Histogram,
+    pub pg_timeouts: IntCounterVec,         // kind: wait, create, recycle
+    pub pg_recycle_failures: IntCounterVec, // pool, reason: cancelled, closed, error, timeout
+    pub pg_breaker_state: IntGaugeVec,      // pool
+    pub pg_breaker_rejections: IntCounterVec, // pool
+    pub pg_read_retries: IntCounter,
+    cache_entries: IntGauge,
+    pub cache_lookups: IntCounterVec,       // result: hit, miss
+    pub cache_evictions: IntCounterVec,     // cause: expired, size
+    pub cache_invalidations: IntCounterVec, // kind: key, namespace
+    pub cache_invalidation_listener: IntGauge,
+    pub sessions_created: IntCounter,
+    pub auth_failures: IntCounterVec,       // reason
+    pub jobs_queued: IntGauge,
+    pub jobs_processed: IntCounterVec,      // kind, outcome: succeeded, retried, dead
+    pub jobs_duration: HistogramVec,        // kind
+    pub jobs_deduplicated: IntCounter,
+    pub jobs_rejected: IntCounter,
+    pub rate_limited: IntCounterVec,        // scope
+}
+
+
+// Registered once, shared by the middleware, the handlers and the background tasks
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+
+
+pub fn get() -> &'static Metrics {
+    &METRICS
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Metrics {
+    fn new() -> Self {
+        let http_labels = &["method", "route", "status"];
+
+        let metrics = Metrics {
+            registry: Registry::new(),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                http_labels,
+            ).unwrap(),
+            http_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time spent handling HTTP requests"),
+                http_labels,
+            ).unwrap(),
+            pg_pool: IntGaugeVec::new(
+                Opts::new("pg_pool_connections", "Postgres pool connections (max_size, size, available = idle, waiting = tasks waiting for one)"),
+                &["pool", "state"],
+            ).unwrap(),
+            pg_checkout_duration: Histogram::with_opts(
+                HistogramOpts::new("pg_pool_checkout_duration_seconds", "Time spent getting a connection from a Postgres pool")
+                    .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            ).unwrap(),
+            pg_timeouts: IntCounterVec::new(
+                Opts::new("pg_pool_timeouts_total", "Postgres pool checkouts that timed out"),
+                &["kind"],
+            ).unwrap(),
+            pg_recycle_failures: IntCounterVec::new(
+                Opts::new("pg_pool_recycle_failures_total", "Pooled connections dropped because the recycle check failed"),
+                &["pool", "reason"],
+            ).unwrap(),
+            pg_breaker_state: IntGaugeVec::new(
+                Opts::new("pg_breaker_state", "Circuit breaker of a Postgres pool (0 = closed, 1 = half-open, 2 = open)"),
+                &["pool"],
+            ).unwrap(),
+            pg_breaker_rejections: IntCounterVec::new(
+                Opts::new("pg_breaker_rejections_total", "Postgres checkouts refused by an open circuit breaker"),
+                &["pool"],
+            ).unwrap(),
+            pg_read_retries: IntCounter::new("pg_read_retries_total", "Idempotent reads run again after a transient Postgres error").unwrap(),
+            cache_entries: IntGauge::new("cache_entries", "Entries in the in-memory cache (approximate)").unwrap(),
+            cache_lookups: IntCounterVec::new(
+                Opts::new("cache_lookups_total", "In-memory cache lookups"),
+                &["result"],
+            ).unwrap(),
+            cache_evictions: IntCounterVec::new(
+                Opts::new("cache_evictions_total", "Entries evicted from the in-memory cache"),
+                &["cause"],
+            ).unwrap(),
+            cache_invalidations: IntCounterVec::new(
+                Opts::new("cache_invalidations_total", "Cache evictions received from the invalidation channel (sent by any instance)"),
+                &["kind"],
+            ).unwrap(),
+            cache_invalidation_listener: IntGauge::new("cache_invalidation_listener_up", "1 while the cache invalidation listener is connected").unwrap(),
+            sessions_created: IntCounter::new("sessions_created_total", "Sessions created").unwrap(),
+            auth_failures: IntCounterVec::new(
+                Opts::new("auth_failures_total", "Requests rejected by the session or admin token check"),
+                &["reason"],
+            ).unwrap(),
+            jobs_queued: IntGauge::new("jobs_queued", "Background jobs waiting in the jobs table (sampled by the workers)").unwrap(),
+            jobs_processed: IntCounterVec::new(
+                Opts::new("jobs_processed_total", "Background job attempts by outcome (retried attempts, then succeeded or dead once per job)"),
+                &["kind", "outcome"],
+            ).unwrap(),
+            jobs_duration: HistogramVec::new(
+                HistogramOpts::new("job_duration_seconds", "Time spent running an attempt of a background job"),
+                &["kind"],
+            ).unwrap(),
+            jobs_deduplicated: IntCounter::new("jobs_deduplicated_total", "Jobs not queued, a job with the same dedupe key was waiting").unwrap(),
+            jobs_rejected: IntCounter::new("jobs_rejected_total", "Jobs not queued, too many jobs were waiting (JOBS_MAX_QUEUED)").unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "Requests rejected by a rate limit"),
+                &["scope"],
+            ).unwrap(),
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 22] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_duration.clone()),
+            Box::new(metrics.pg_pool.clone()),
+            Box::new(metrics.pg_checkout_duration.clone()),
+            Box::new(metrics.pg_timeouts.clone()),
+            Box::new(metrics.pg_recycle_failures.clone()),
+            Box::new(metrics.pg_breaker_state.clone()),
+            Box::new(metrics.pg_breaker_rejections.clone()),
+            Box::new(metrics.pg_read_retries.clone()),
+            Box::new(metrics.cache_entries.clone()),
+            Box::new(metrics.cache_lookups.clone()),
+            Box::new(metrics.cache_evictions.clone()),
+            Box::new(metrics.cache_invalidations.clone()),
+            Box::new(metrics.cache_invalidation_listener.clone()),
+            Box::new(metrics.sessions_created.clone()),
+            Box::new(metrics.auth_failures.clone()),
+            Box::new(metrics.jobs_queued.clone()),
+            Box::new(metrics.jobs_processed.clone()),
+            Box::new(metrics.jobs_duration.clone()),
+            Box::new(metrics.jobs_deduplicated.clone()),
+            Box::new(metrics.jobs_rejected.clone()),
+            Box::new(metrics.rate_limited.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    /// Sample the pool and cache gauges, then encode everything in the Prometheus text format
+    pub fn render(&self, db: &ReplicaSet, cache: &AppCache) -> String {
+        for (name, pool) in db.pools() {
+            let status = pool.status();
+            for (state, value) in [
+                ("max_size", status.max_size),
+                ("size", status.size),
+                ("available", status.available),
+                ("waiting", status.waiting),
+            ] {
+                self.pg_pool.with_label_values(&[name, state]).set(value as i64);
+            }
+        }
+        self.cache_entries.set(cache.entry_count() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
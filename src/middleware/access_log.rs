@@ -0,0 +1,98 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    body::{BodySize, MessageBody},
+    http::header::{self, HeaderMap},
+    middleware::Next,
+    Error,
+    web,
+};
+use std::{collections::BTreeMap, time::Instant};
+
+
+/// Access log options, shared as app data
+pub struct AccessLog {
+    pub log_headers: bool,
+}
+
+
+// Headers carrying credentials, their value is never logged
+const SENSITIVE_HEADERS: [&str; 5] = ["authorization", "proxy-authorization", "x-csrf-token", "x-api-key", "set-cookie"];
+const REDACTED: &str = "[redacted]";
+
+
+
+/// Request headers with the credentials redacted, cookies keep their names only
+fn redacted_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if *name == header::COOKIE {
+                value
+                    .to_str()
+                    .unwrap_or_default()
+                    .split(';')
+                    .map(|cookie| format!("{}={}", cookie.split('=').next().unwrap_or_default().trim(), REDACTED))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            } else if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+
+/// One `access` log line per request: method, path, status, latency, response size and client address
+pub async fn log_access<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let client_ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let headers = req
+        .app_data::<web::Data<AccessLog>>()
+        .is_some_and(|options| options.log_headers)
+        .then(|| redacted_headers(req.headers()));
+
+    let result = next.call(req).await;
+
+    let (status, bytes) = match &result {
+        Ok(res) => {
+            let bytes = match res.response().body().size() {
+                BodySize::Sized(n) => Some(n),
+                BodySize::None => Some(0),
+                BodySize::Stream => None,
+            };
+            (res.status(), bytes)
+        }
+        Err(e) => (e.as_response_error().status_code(), None),
+    };
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    log::info!(
+        target: "access",
+        method = method.as_str(),
+        path = path.as_str(),
+        status = status.as_u16(),
+        latency_ms = (latency_ms * 1000.0).round() / 1000.0,
+        bytes:serde = bytes,
+        client_ip = client_ip.as_str(),
+        forwarded_for:serde = forwarded_for,
+        headers:serde = headers;
+        "{} {} {} {:.3}ms", method, path, status.as_u16(), latency_ms
+    );
+
+    result
+}
@@ -5,17 +5,21 @@ use actix_web::{
     },
     body::MessageBody,
     middleware::Next,
+    http::header,
     HttpResponse,
     HttpMessage,
     Error,
     web,
 };
 use crate::{
-    models::user::SessionUser,
+    models::{initial::ApiSettings, user::SessionUser},
     utils::{
         AppCache,
+        get_cached,
         make_key
-    }
+    },
+    logging,
+    metrics
 };
 
 
@@ -38,6 +42,7 @@ use crate::{
 /// Check for valid session based on Session-ID cookie and x-csrf-token header
 /// Inserts SessionUser into request extensions if valid
 /// Returns true if valid, false otherwise
+#[tracing::instrument(name = "auth.session_check", skip_all)]
 async fn session_check(req: &ServiceRequest) -> bool {
     // Look for Session-ID cookie and x-csrf-token header
     let session_id = req
@@ -51,6 +56,7 @@ async fn session_check(req: &ServiceRequest) -> bool {
 
     // If either is missing, fail
     if session_id.is_none() || csrf_token.is_none() {
+        metrics::get().auth_failures.with_label_values(&["missing_credentials"]).inc();
         return false;
     }
 
@@ -58,22 +64,61 @@ async fn session_check(req: &ServiceRequest) -> bool {
     let cache = req.app_data::<web::Data<AppCache>>().unwrap();
     let key = make_key(session_id.unwrap());
 
-    if let Some(user) = cache.get(&key).await {
+    if let Some(user) = get_cached(&key, cache).await {
         // Convert the JSON string back to SessionUser
         let user: SessionUser = serde_json::from_str(&user).unwrap();
         
         // Verify CSRF token
         if user.csrf_token != csrf_token.unwrap() {
+            metrics::get().auth_failures.with_label_values(&["csrf_mismatch"]).inc();
             return false;
         }
 
-        // Insert user into request extensions for further use
+        // Insert user into request extensions for further use, and tag the request's logs with it
+        logging::set_user(&user.user_name);
         req.extensions_mut().insert(user);
 
-        return true;
+        true
     } else {
-        return false;
+        metrics::get().auth_failures.with_label_values(&["unknown_session"]).inc();
+        false
+    }
+}
+
+
+/// Compare two secrets in a time that does not depend on where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+
+/// Admin middleware
+/// Checks the `Authorization: Bearer <API_ADMIN_TOKEN>` header, every request fails while no token is configured
+/// Short-circuits with 401 Unauthorized if the check fails
+pub async fn admin_check<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse, Error>
+    where B: MessageBody + 'static
+{
+    let expected = req.app_data::<web::Data<ApiSettings>>().and_then(|settings| settings.admin_token.clone());
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let valid = matches!((&expected, given), (Some(expected), Some(given)) if constant_time_eq(expected.as_bytes(), given.as_bytes()));
+    if !valid {
+        log::warn!("Admin token check failed for request {} {}", req.method(), req.path());
+        metrics::get().auth_failures.with_label_values(&["admin_token"]).inc();
+
+        let resp = HttpResponse::Unauthorized()
+            .append_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .append_header(("content-type", "text/plain; charset=utf-8"))
+            .body("Unauthorized: missing or invalid admin token");
+        return Ok(req.into_response(resp).map_into_boxed_body());
     }
+
+    let res = next.call(req).await?;
+    Ok(res.map_into_boxed_body())
 }
 
 
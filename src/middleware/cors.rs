@@ -0,0 +1,289 @@
+use actix_web::{
+    dev::RequestHead,
+    http::header::HeaderValue,
+    web,
+};
+use crate::{models::initial::CorsSettings, utils::{match_scope, parse_scope_override}};
+use std::{fmt, str::FromStr, sync::RwLock};
+use actix_cors::Cors;
+
+
+/// An allowed `Origin`: `*`, an exact origin or every subdomain of one (`https://*.example.com`)
+#[derive(Clone, PartialEq, Debug)]
+pub enum OriginPattern {
+    Any,
+    Exact(String),
+    Subdomains { scheme: String, domain: String }, // `domain` keeps its port, if any
+}
+
+
+/// Allowed origins of the API, with overrides for some scopes (`/health`, `/auth`, ...)
+#[derive(Clone, Default)]
+pub struct CorsOrigins {
+    pub default: Vec<OriginPattern>,
+    pub scopes: Vec<(String, Vec<OriginPattern>)>, // Empty list for a scope with CORS off
+}
+
+
+/// Origins shared by the CORS middleware of every worker, replaced on reload
+pub struct AllowedOrigins(RwLock<CorsOrigins>);
+
+
+
+/// Parse a `<scope>=<origin>|<origin>` (or `<scope>=off`) override of `API_CORS_SCOPE_ORIGINS`
+pub fn parse_scope_origins(s: &str) -> Result<(String, Vec<OriginPattern>), String> {
+    let (scope, origins) = parse_scope_override(s)?;
+    let origins = match origins {
+        "off" => Vec::new(),
+        origins => origins.split('|').map(str::parse).collect::<Result<_, _>>()?,
+    };
+    Ok((scope, origins))
+}
+
+
+/// CORS middleware built from the settings, the origins are looked up in `origins` on every request
+pub fn build(settings: &CorsSettings, origins: web::Data<AllowedOrigins>) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_origin_fn(move |origin, head| origins.allows(origin, head))
+        .allowed_methods(settings.allowed_methods.iter().map(String::as_str))
+        .expose_headers(settings.exposed_headers.iter().map(String::as_str))
+        .max_age(settings.max_age);
+
+    cors = if settings.allowed_headers.iter().any(|h| h == "*") {
+        cors.allow_any_header()
+    } else {
+        cors.allowed_headers(settings.allowed_headers.iter().map(String::as_str))
+    };
+    if settings.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+
+    cors
+}
+
+
+// ------- Implementations ------- //
+
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "*" {
+            return Ok(OriginPattern::Any);
+        }
+
+        let invalid = || format!("'{}' is not an origin, expected *, <scheme>://<host>[:<port>] or <scheme>://*.<domain>", s);
+        let (scheme, host) = s.split_once("://").ok_or_else(invalid)?;
+        if !matches!(scheme, "http" | "https") || host.is_empty() || host.contains(['/', '?', '#', '@']) {
+            return Err(invalid());
+        }
+
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                Ok(OriginPattern::Subdomains { scheme: scheme.to_string(), domain: domain.to_string() })
+            }
+            Some(_) => Err(invalid()),
+            None if host.contains('*') => Err(invalid()),
+            None => Ok(OriginPattern::Exact(s)),
+        }
+    }
+}
+
+
+impl fmt::Display for OriginPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginPattern::Any => write!(f, "*"),
+            OriginPattern::Exact(origin) => write!(f, "{}", origin),
+            OriginPattern::Subdomains { scheme, domain } => write!(f, "{}://*.{}", scheme, domain),
+        }
+    }
+}
+
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            OriginPattern::Subdomains { scheme, domain } => {
+                let origin = origin.to_ascii_lowercase();
+                origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|rest| rest.strip_prefix("://"))
+                    .and_then(|host| host.strip_suffix(domain.as_str()))
+                    .and_then(|sub| sub.strip_suffix('.'))
+                    .is_some_and(|sub| !sub.is_empty() && !sub.contains(['/', ':']))
+            }
+        }
+    }
+}
+
+
+impl CorsOrigins {
+    /// Origins allowed on a path, from the longest matching scope override or the default list
+    fn for_path(&self, path: &str) -> &[OriginPattern] {
+        match_scope(&self.scopes, path).unwrap_or(&self.default)
+    }
+}
+
+
+impl AllowedOrigins {
+    pub fn new(origins: CorsOrigins) -> Self {
+        AllowedOrigins(RwLock::new(origins))
+    }
+
+    /// Replace the origins, used on reload
+    pub fn set(&self, origins: CorsOrigins) {
+        *self.0.write().unwrap() = origins;
+    }
+
+    fn allows(&self, origin: &HeaderValue, head: &RequestHead) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        self.0.read().unwrap().for_path(head.uri.path()).iter().any(|pattern| pattern.matches(origin))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::{header, Method, StatusCode}, test, web, App, HttpResponse};
+    use crate::models::initial::CorsSettings;
+    use super::{build, AllowedOrigins, CorsOrigins};
+
+    fn settings() -> CorsSettings {
+        CorsSettings {
+            origins: CorsOrigins {
+                default: vec!["https://app.example.com".parse().unwrap(), "https://*.example.org".parse().unwrap()],
+                scopes: vec![
+                    super::parse_scope_origins("/health=*").unwrap(),
+                    super::parse_scope_origins("/auth/admin=off").unwrap(),
+                ],
+            },
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: ["content-type", "x-csrf-token"].map(String::from).to_vec(),
+            exposed_headers: ["x-csrf-token", "x-request-id"].map(String::from).to_vec(),
+            allow_credentials: true,
+            max_age: 600,
+        }
+    }
+
+    async fn preflight(origins: &web::Data<AllowedOrigins>, uri: &str, origin: &str, method: &str, headers: Option<&str>) -> actix_web::dev::ServiceResponse {
+        let cors = build(&settings(), origins.clone());
+        let app = test::init_service(App::new().wrap(cors).default_service(web::to(HttpResponse::Ok))).await;
+
+        let mut req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri(uri)
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method));
+        if let Some(headers) = headers {
+            req = req.insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers));
+        }
+        test::call_service(&app, req.to_request()).await.map_into_boxed_body()
+    }
+
+    fn allowed_origin(res: &actix_web::dev::ServiceResponse) -> Option<&str> {
+        res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).and_then(|v| v.to_str().ok())
+    }
+
+    #[actix_web::test]
+    async fn preflight_allows_configured_origin() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+
+        for method in ["PUT", "PATCH", "DELETE"] {
+            let res = preflight(&origins, "/v1/sample_db/update-note/1", "https://app.example.com", method, Some("content-type, x-csrf-token")).await;
+            assert_eq!(res.status(), StatusCode::OK, "{} preflight", method);
+            assert_eq!(allowed_origin(&res), Some("https://app.example.com"));
+            assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+            assert_eq!(res.headers().get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+            let methods = res.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap().to_str().unwrap();
+            assert!(methods.contains(method), "{} missing from {}", method, methods);
+        }
+    }
+
+    #[actix_web::test]
+    async fn preflight_matches_wildcard_subdomains() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+
+        for origin in ["https://a.example.org", "https://a.b.example.org"] {
+            let res = preflight(&origins, "/v1/auth/session", origin, "POST", None).await;
+            assert_eq!(allowed_origin(&res), Some(origin));
+        }
+        for origin in ["https://example.org", "https://evil-example.org", "http://a.example.org", "https://a.example.org.evil.com"] {
+            let res = preflight(&origins, "/v1/auth/session", origin, "POST", None).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{} should be rejected", origin);
+            assert_eq!(allowed_origin(&res), None);
+        }
+    }
+
+    #[actix_web::test]
+    async fn preflight_rejects_unknown_origin_method_and_headers() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+
+        let res = preflight(&origins, "/v1/auth/session", "https://evil.com", "POST", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(allowed_origin(&res), None);
+
+        let res = preflight(&origins, "/v1/auth/session", "https://app.example.com", "TRACE", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = preflight(&origins, "/v1/auth/session", "https://app.example.com", "POST", Some("x-custom")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn scopes_override_the_default_origins() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+
+        // `/health=*` on every version and the unversioned paths
+        for uri in ["/health/live", "/v1/health/live", "/v2/health"] {
+            let res = preflight(&origins, uri, "https://status.other.com", "GET", None).await;
+            assert_eq!(allowed_origin(&res), Some("https://status.other.com"), "{}", uri);
+        }
+        // `/auth/admin=off`, but not `/auth/administrators`
+        let res = preflight(&origins, "/v1/auth/admin/x", "https://app.example.com", "GET", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = preflight(&origins, "/v1/auth/administrators", "https://app.example.com", "GET", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // A `/v` prefix that is not a version is not stripped
+        let res = preflight(&origins, "/vx/health", "https://status.other.com", "GET", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn reload_replaces_the_origins() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+        let res = preflight(&origins, "/v1/auth/session", "https://new.example.com", "POST", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        origins.set(CorsOrigins { default: vec!["https://new.example.com".parse().unwrap()], scopes: Vec::new() });
+        let res = preflight(&origins, "/v1/auth/session", "https://new.example.com", "POST", None).await;
+        assert_eq!(allowed_origin(&res), Some("https://new.example.com"));
+    }
+
+    #[actix_web::test]
+    async fn responses_expose_headers() {
+        let origins = web::Data::new(AllowedOrigins::new(settings().origins));
+        let app = test::init_service(App::new().wrap(build(&settings(), origins)).default_service(web::to(HttpResponse::Ok))).await;
+
+        let req = test::TestRequest::get().uri("/v1/auth/session").insert_header((header::ORIGIN, "https://app.example.com")).to_request();
+        let res = test::call_service(&app, req).await.map_into_boxed_body();
+        assert_eq!(allowed_origin(&res), Some("https://app.example.com"));
+        let exposed = res.headers().get(header::ACCESS_CONTROL_EXPOSE_HEADERS).unwrap().to_str().unwrap();
+        assert!(exposed.contains("x-csrf-token") && exposed.contains("x-request-id"), "exposed: {}", exposed);
+    }
+
+    #[actix_web::test]
+    async fn invalid_origins_are_rejected() {
+        for origin in ["app.example.com", "ftp://x.com", "https://x.com/path", "https://a.*.com", "https://*.", "https://"] {
+            assert!(origin.parse::<super::OriginPattern>().is_err(), "{} should be invalid", origin);
+        }
+        assert!(super::parse_scope_origins("health=*").is_err());
+        assert!(super::parse_scope_origins("/health").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    body::MessageBody,
+    middleware::Next,
+    Error,
+    web,
+};
+use crate::database::timeout::with_deadline;
+use std::time::Duration;
+
+
+/// Time the queries of a request have from its start (`PG_REQUEST_TIMEOUT`), shared as app data
+pub struct QueryDeadline {
+    pub timeout: Option<Duration>,
+}
+
+
+
+/// Run the request with a deadline for its queries, the ones still running past it are cancelled (504)
+pub async fn set_query_deadline<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let timeout = req.app_data::<web::Data<QueryDeadline>>().and_then(|deadline| deadline.timeout);
+    match timeout {
+        Some(timeout) => with_deadline(timeout, next.call(req)).await,
+        None => next.call(req).await,
+    }
+}
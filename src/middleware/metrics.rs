@@ -0,0 +1,38 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    body::MessageBody,
+    middleware::Next,
+    Error,
+};
+use crate::metrics;
+use std::time::Instant;
+
+
+
+/// Count requests and record their latency per method, route pattern and status
+pub async fn record_metrics<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let result = next.call(req).await;
+
+    // The route pattern (`/v1/sample_db/notes/{id}`) keeps the label cardinality bounded
+    let (route, status) = match &result {
+        Ok(res) => (
+            res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string()),
+            res.status(),
+        ),
+        Err(e) => ("unmatched".to_string(), e.as_response_error().status_code()),
+    };
+
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let metrics = metrics::get();
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics.http_duration.with_label_values(&labels).observe(start.elapsed().as_secs_f64());
+
+    result
+}
@@ -1 +1,11 @@
 pub mod auth;
+pub mod versioning;
+pub mod shutdown;
+pub mod metrics;
+pub mod trace;
+pub mod request_id;
+pub mod access_log;
+pub mod cors;
+pub mod security_headers;
+pub mod rate_limit;
+pub mod deadline;
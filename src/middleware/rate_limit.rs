@@ -0,0 +1,122 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    body::{BoxBody, MessageBody},
+    middleware::Next,
+    HttpMessage,
+    ResponseError,
+    Error,
+    web,
+};
+use crate::{
+    models::{errors::AppError, user::SessionUser},
+    rate_limit::{Decision, KeyKind, Policy, RateLimiter, Scope},
+    metrics
+};
+
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATE_LIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+
+
+/// Client address, from the proxy headers only when they are trusted
+fn client_ip(req: &ServiceRequest, trust_forwarded: bool) -> String {
+    if trust_forwarded && let Some(ip) = req.connection_info().realip_remote_addr() {
+        return ip.to_string();
+    }
+    req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string())
+}
+
+
+/// Bucket of the request for the policy's key kind
+fn client_key(req: &ServiceRequest, policy: &Policy, trust_forwarded: bool) -> String {
+    match policy.key {
+        KeyKind::User => {
+            if let Some(user) = req.extensions().get::<SessionUser>() {
+                return format!("user:{}", user.user_name);
+            }
+        }
+        KeyKind::Ip => {}
+    }
+    format!("ip:{}", client_ip(req, trust_forwarded))
+}
+
+
+/// Set the `RateLimit-*` headers, unless an enclosing policy already set tighter ones
+fn set_headers(headers: &mut HeaderMap, policy: &Policy, decision: &Decision) {
+    let tighter_set = headers
+        .get(&RATE_LIMIT_REMAINING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .is_some_and(|remaining| remaining <= decision.remaining);
+    if tighter_set {
+        return;
+    }
+
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(decision.reset.as_secs_f64().ceil() as u64));
+    if let Ok(value) = HeaderValue::from_str(&policy.header()) {
+        headers.insert(RATE_LIMIT_POLICY, value);
+    }
+}
+
+
+/// Count the request against the scope's policy, 429 with `Retry-After` once the limit is reached
+async fn limit<B>(scope: Scope, req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, Error>
+    where B: MessageBody + 'static
+{
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Some(policy) = limiter.policy(scope) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let key = client_key(&req, &policy, limiter.trust_forwarded());
+    let decision = limiter.check(scope, &policy, &key).await;
+
+    if !decision.allowed {
+        log::warn!("Rate limit '{}' reached for {}", scope.name(), key);
+        metrics::get().rate_limited.with_label_values(&[scope.name()]).inc();
+
+        let mut res = AppError::TooManyRequests(decision.retry_after).error_response();
+        set_headers(res.headers_mut(), &policy, &decision);
+        res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(decision.retry_after.as_secs_f64().ceil() as u64));
+        return Ok(req.into_response(res));
+    }
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    set_headers(res.headers_mut(), &policy, &decision);
+    Ok(res)
+}
+
+
+/// Limit applied to every request
+pub async fn limit_default<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, Error>
+    where B: MessageBody + 'static
+{
+    limit(Scope::Default, req, next).await
+}
+
+
+/// Limit on session creation, so clients can not fill the cache with sessions
+pub async fn limit_session_create<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, Error>
+    where B: MessageBody + 'static
+{
+    limit(Scope::SessionCreate, req, next).await
+}
+
+
+/// Limit on note creation
+pub async fn limit_note_create<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, Error>
+    where B: MessageBody + 'static
+{
+    limit(Scope::NoteCreate, req, next).await
+}
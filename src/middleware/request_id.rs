@@ -0,0 +1,45 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    http::header::{HeaderName, HeaderValue},
+    body::MessageBody,
+    middleware::Next,
+    Error,
+};
+use crate::logging;
+
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+
+
+/// Accept the caller's id only if it is short and made of safe characters (it ends up in the logs)
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+
+/// Propagate the caller's `X-Request-Id` or generate one, attach it to the request's logs and echo it in the response
+pub async fn assign_request_id<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let header_value = HeaderValue::from_str(&request_id).ok();
+    let mut res = logging::with_request(request_id, next.call(req)).await?;
+
+    if let Some(value) = header_value {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(res)
+}
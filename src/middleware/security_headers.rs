@@ -0,0 +1,97 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    http::header::{self, HeaderName, HeaderValue},
+    body::MessageBody,
+    middleware::Next,
+    HttpMessage,
+    Error,
+    web,
+};
+use crate::{models::initial::SecurityHeadersSettings, utils::match_scope};
+
+
+/// Headers added to every response, built once from the settings and shared as app data
+pub struct SecurityHeaders {
+    hsts: Option<HeaderValue>,                     // Only sent over HTTPS
+    fixed: Vec<(HeaderName, HeaderValue)>,
+    csp: Option<String>,
+    csp_scopes: Vec<(String, Option<String>)>,     // None for a scope without CSP
+}
+
+
+/// Per request CSP nonce, `{nonce}` in the policy is replaced by it (HTML handlers put it on their inline scripts)
+#[derive(Clone)]
+pub struct CspNonce(pub String);
+
+
+// Replaced by the request's nonce in the Content-Security-Policy
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+
+
+/// Add the security headers to the response, headers already set by the handler are kept
+pub async fn set_security_headers<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let Some(security) = req.app_data::<web::Data<SecurityHeaders>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let https = req.connection_info().scheme() == "https";
+    let csp = security.csp_for(req.path()).map(|policy| {
+        if !policy.contains(NONCE_PLACEHOLDER) {
+            return policy.to_string();
+        }
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        let policy = policy.replace(NONCE_PLACEHOLDER, &nonce);
+        req.extensions_mut().insert(CspNonce(nonce));
+        policy
+    });
+
+    let mut res = next.call(req).await?;
+
+    let headers = res.headers_mut();
+    let csp = csp.and_then(|policy| HeaderValue::from_str(&policy).ok()).map(|value| (header::CONTENT_SECURITY_POLICY, value));
+    let hsts = security.hsts.clone().filter(|_| https).map(|value| (header::STRICT_TRANSPORT_SECURITY, value));
+    for (name, value) in security.fixed.iter().cloned().chain(csp).chain(hsts) {
+        if !headers.contains_key(&name) {
+            headers.insert(name, value);
+        }
+    }
+
+    Ok(res)
+}
+
+
+// ------- Implementations ------- //
+
+
+impl SecurityHeaders {
+    pub fn new(settings: &SecurityHeadersSettings) -> Self {
+        let value = |v: &Option<String>| v.as_deref().and_then(|v| HeaderValue::from_str(v).ok());
+        let fixed = [
+            (header::X_CONTENT_TYPE_OPTIONS, Some(HeaderValue::from_static("nosniff"))),
+            (header::X_FRAME_OPTIONS, value(&settings.frame_options)),
+            (header::REFERRER_POLICY, value(&settings.referrer_policy)),
+            (HeaderName::from_static("permissions-policy"), value(&settings.permissions_policy)),
+            (HeaderName::from_static("cross-origin-opener-policy"), value(&settings.opener_policy)),
+            (HeaderName::from_static("cross-origin-resource-policy"), value(&settings.resource_policy)),
+            (HeaderName::from_static("cross-origin-embedder-policy"), value(&settings.embedder_policy)),
+        ];
+
+        SecurityHeaders {
+            hsts: value(&settings.hsts),
+            fixed: fixed.into_iter().filter_map(|(name, value)| Some((name, value?))).collect(),
+            csp: settings.csp.clone(),
+            csp_scopes: settings.csp_scopes.clone(),
+        }
+    }
+
+    /// CSP of the path, from the longest matching scope override or the default one
+    fn csp_for(&self, path: &str) -> Option<&str> {
+        match_scope(&self.csp_scopes, path).unwrap_or(&self.csp).as_deref()
+    }
+}
@@ -0,0 +1,53 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    body::{BodySize, BoxBody, MessageBody},
+    middleware::Next,
+    web::{self, Bytes},
+    Error,
+};
+use crate::shutdown::{Lifecycle, RequestGuard};
+use std::{pin::Pin, task::{Context, Poll}};
+
+
+/// Response body holding the request's guard, the request is in flight until the body is sent (or dropped)
+struct GuardedBody {
+    body: BoxBody,
+    _guard: RequestGuard,
+}
+
+
+
+/// Counts in-flight requests so shutdown can wait for them to finish, streamed bodies included
+pub async fn track_requests<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse, Error>
+    where B: MessageBody + 'static
+{
+    let guard = req
+        .app_data::<web::Data<Lifecycle>>()
+        .cloned()
+        .map(RequestGuard::new);
+
+    let res = next.call(req).await?.map_into_boxed_body();
+    Ok(match guard {
+        Some(guard) => res.map_body(|_, body| BoxBody::new(GuardedBody { body, _guard: guard })),
+        None => res,
+    })
+}
+
+
+// ------- Implementations ------- //
+
+
+impl MessageBody for GuardedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
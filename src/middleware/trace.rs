@@ -0,0 +1,53 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    body::MessageBody,
+    middleware::Next,
+    Error,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing::{field::Empty, Instrument};
+use crate::telemetry;
+
+
+
+/// Open a server span per request, continuing the caller's trace (`traceparent`) and returning ours
+pub async fn trace_requests<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error>
+    where B: MessageBody + 'static
+{
+    let span = tracing::info_span!(
+        "HTTP request",
+        otel.name = Empty,
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        http.route = Empty,
+        url.path = %req.path(),
+        http.response.status_code = Empty,
+    );
+    let _ = span.set_parent(telemetry::extract_context(req.headers()));
+
+    let method = req.method().clone();
+    let result = next.call(req).instrument(span.clone()).await;
+
+    let status = match &result {
+        Ok(res) => {
+            let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+            span.record("otel.name", format!("{} {}", method, route));
+            span.record("http.route", route);
+            res.status()
+        }
+        Err(e) => e.as_response_error().status_code(),
+    };
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    result.map(|mut res| {
+        telemetry::inject_context(&span.context(), res.headers_mut());
+        res
+    })
+}
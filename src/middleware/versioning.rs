@@ -0,0 +1,147 @@
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse
+    },
+    http::{
+        header::{self, HeaderName, HeaderValue, HttpDate},
+        Uri
+    },
+    body::MessageBody,
+    middleware::Next,
+    HttpResponse,
+    Error,
+    web,
+};
+use crate::{
+    models::initial::ApiSettings,
+    routes::VERSIONED_SCOPES
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Vendor media type used for `Accept` negotiation: application/vnd.rust-api.v2+json
+const VENDOR_MEDIA_PREFIX: &str = "application/vnd.rust-api.v";
+const API_VERSION_HEADER: HeaderName = HeaderName::from_static("api-version");
+const DEPRECATION_HEADER: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET_HEADER: HeaderName = HeaderName::from_static("sunset");
+
+
+
+/// Version prefix of a path (`/v1/...` -> Some(1)), None for unversioned paths
+fn path_version(path: &str) -> Option<u8> {
+    let rest = path.strip_prefix("/v")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+
+/// True if the path belongs to one of the scopes mounted under every version
+fn is_versioned_scope(path: &str) -> bool {
+    VERSIONED_SCOPES
+        .iter()
+        .any(|scope| path.strip_prefix(scope).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+}
+
+
+/// `Deprecation` value (RFC 9745): the date as a structured field, `@` and the seconds since the epoch
+fn deprecation_date(date: HttpDate) -> HeaderValue {
+    let seconds = SystemTime::from(date).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    HeaderValue::from_str(&format!("@{}", seconds)).expect("a number is a valid header value")
+}
+
+
+/// Version asked for in the `Accept` header, if any
+fn accept_version(req: &ServiceRequest) -> Option<u8> {
+    let accept = req.headers().get(header::ACCEPT)?.to_str().ok()?;
+
+    accept.split(',').find_map(|media| {
+        let media = media.split(';').next()?.trim();
+        let rest = media.strip_prefix(VENDOR_MEDIA_PREFIX)?;
+        rest.split('+').next()?.parse().ok()
+    })
+}
+
+
+fn api_settings(req: &ServiceRequest) -> web::Data<ApiSettings> {
+    req.app_data::<web::Data<ApiSettings>>()
+        .cloned()
+        .unwrap_or_else(|| web::Data::new(ApiSettings::default()))
+}
+
+
+/// Accept-header version negotiation (only when `API_VERSION_NEGOTIATION` is enabled)
+/// Rewrites unversioned paths to `/v{N}/...` before routing, N comes from the `Accept`
+/// header or falls back to the default version. Must wrap the whole `App`
+pub async fn negotiate_version<B>(mut req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse, Error>
+    where B: MessageBody + 'static
+{
+    let settings = api_settings(&req);
+    if !settings.version_negotiation || path_version(req.path()).is_some() || !is_versioned_scope(req.path()) {
+        let res = next.call(req).await?;
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let version = accept_version(&req).unwrap_or(settings.default_version);
+    if !ApiSettings::SUPPORTED_VERSIONS.contains(&version) {
+        log::warn!("Unsupported API version {} requested for {} {}", version, req.method(), req.path());
+
+        // Short-circuit and return 406 Not Acceptable
+        let resp = HttpResponse::NotAcceptable()
+            .append_header(("content-type", "text/plain; charset=utf-8"))
+            .body(format!("Unsupported API version: v{}", version));
+
+        return Ok(req.into_response(resp).map_into_boxed_body());
+    }
+
+    // Rewrite the URI so the router matches the versioned scope
+    let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let uri: Uri = format!("/v{}{}", version, path_and_query)
+        .parse()
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+    headers.insert(API_VERSION_HEADER, HeaderValue::from(u16::from(version)));
+    headers.append(header::VARY, HeaderValue::from_static("Accept"));
+
+    Ok(res.map_into_boxed_body())
+}
+
+
+/// Adds `Deprecation`, `Sunset` and `Link` headers to responses of deprecated API versions
+/// Unversioned paths are always deprecated in favour of the default version,
+/// `/v1` is deprecated once `API_V1_DEPRECATION` or `API_V1_SUNSET` is set
+pub async fn deprecation_headers<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse, Error>
+    where B: MessageBody + 'static
+{
+    let settings = api_settings(&req);
+    let path = req.path().to_string();
+
+    // The legacy scope matches every path, a 404 of an unknown one is not a deprecated route
+    let deprecation = match path_version(&path) {
+        _ if req.match_pattern().is_none() => None,
+        None => Some((settings.legacy_deprecation, settings.legacy_sunset, format!("/v{}{}", settings.default_version, path))),
+        Some(1) => settings.v1_deprecation.or(settings.v1_sunset).map(|since| (since, settings.v1_sunset, format!("/v2{}", &path[3..]))),
+        Some(_) => None,
+    };
+
+    let mut res = next.call(req).await?;
+
+    if let Some((since, sunset, successor)) = deprecation {
+        let headers = res.headers_mut();
+        headers.insert(DEPRECATION_HEADER, deprecation_date(since));
+        if let Some(sunset) = sunset
+            && let Ok(value) = HeaderValue::from_str(&sunset.to_string())
+        {
+            headers.insert(SUNSET_HEADER, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+            headers.append(header::LINK, value);
+        }
+    }
+
+    Ok(res.map_into_boxed_body())
+}
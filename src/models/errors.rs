@@ -1,7 +1,11 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{http::{header::RETRY_AFTER, StatusCode}, HttpResponse, ResponseError};
 use deadpool_postgres::PoolError;
+use crate::{database::DbError, jobs::EnqueueError};
+use tokio_postgres::error::SqlState;
 use serde::Serialize;
-use std::fmt;
+use crate::logging;
+use utoipa::ToSchema;
+use std::{fmt, time::Duration};
 
 
 
@@ -9,16 +13,33 @@ use std::fmt;
 pub enum AppError {
     DbPool(PoolError),
     Pg(tokio_postgres::Error),
-    // Unprocessable(String),
-    // NotFound(String),
-    // Conflict(String),
+    Unprocessable(String),
+    NotFound(String),
+    TooManyRequests(Duration),
+    Timeout(String),
+    Unavailable(String, Duration),  // Why, and when to retry
+    Conflict(String),
     // Gone(String),
 }
 
 
-#[derive(Serialize)]
-struct ErrorResp {
-    error: String
+// When a client is told to come back after the job queue refused its work
+const JOB_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+
+/// JSON body of every `AppError` response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResp {
+    error: String,
+    /// Same as the `X-Request-Id` response header, quote it when reporting a problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+
+/// Cancelled by a query timeout, the request deadline or the server's statement_timeout
+fn is_cancelled(e: &tokio_postgres::Error) -> bool {
+    e.code() == Some(&SqlState::QUERY_CANCELED)
 }
 
 
@@ -30,24 +51,45 @@ impl fmt::Display for AppError {
         match self {
             AppError::DbPool(e) => write!(f, "DB: {}", e),
             AppError::Pg(e) => write!(f, "PostgreSQL: {}", e),
-            // AppError::NotFound(s) => write!(f, "Resource not found: {}", s),
-            // AppError::Conflict(s) => write!(f, "Conflict: {}", s),
+            AppError::NotFound(s) => write!(f, "Resource not found: {}", s),
+            AppError::Conflict(s) => write!(f, "Conflict: {}", s),
             // AppError::Gone(s) => write!(f, "It's gone: {}", s),
-            // AppError::Unprocessable(s) => write!(f, "Unprocessable: {}", s),
+            AppError::Unprocessable(s) => write!(f, "Unprocessable: {}", s),
+            AppError::TooManyRequests(d) => write!(f, "Too many requests, retry in {}s", d.as_secs_f64().ceil()),
+            AppError::Timeout(s) => write!(f, "Timed out: {}", s),
+            AppError::Unavailable(s, d) => write!(f, "Service unavailable: {}, retry in {}s", s, d.as_secs_f64().ceil()),
+        }
+    }
+}
+
+
+impl From<DbError> for AppError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::Pool(PoolError::Backend(e)) if is_cancelled(&e) => AppError::from(e),
+            DbError::Pool(e) => AppError::DbPool(e),
+            DbError::Unavailable(d) => AppError::Unavailable("the database is down".to_string(), d),
         }
     }
 }
 
 
-impl From<PoolError> for AppError {
-    fn from(e: PoolError) -> Self {
-        AppError::DbPool(e)
+impl From<EnqueueError> for AppError {
+    fn from(e: EnqueueError) -> Self {
+        match e {
+            EnqueueError::Full => AppError::Unavailable("too much background work queued".to_string(), JOB_RETRY_AFTER),
+            EnqueueError::Conflict => AppError::Conflict("a job with the same dedupe key is changing, try again".to_string()),
+            EnqueueError::Db(e) => e.into(),
+        }
     }
 }
 
 
 impl From<tokio_postgres::Error> for AppError {
     fn from(e: tokio_postgres::Error) -> Self {
+        if is_cancelled(&e) {
+            return AppError::Timeout("the database query took too long and was cancelled".to_string());
+        }
         AppError::Pg(e)
     }
 }
@@ -58,17 +100,28 @@ impl ResponseError for AppError {
         match self {
             AppError::DbPool(_) => StatusCode::FAILED_DEPENDENCY,
             AppError::Pg(_) => StatusCode::EXPECTATION_FAILED,
-            // AppError::NotFound(_) => StatusCode::NOT_FOUND,
-            // AppError::Conflict(_) => StatusCode::CONFLICT,
-            // AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Unavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
             // AppError::Gone(_) => StatusCode::GONE,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        log::error!("Error occurred: {}", self);
+        // Client errors are expected (a flood of 429s must not look like an outage)
+        if self.status_code().is_server_error() {
+            log::error!("Error occurred: {}", self);
+        } else {
+            log::warn!("Request rejected: {}", self);
+        }
 
-        HttpResponse::build(self.status_code())
-            .json(ErrorResp { error: self.to_string() })
+        let mut res = HttpResponse::build(self.status_code());
+        if let AppError::Unavailable(_, d) = self {
+            res.insert_header((RETRY_AFTER, d.as_secs_f64().ceil().max(1.0) as u64));
+        }
+        res.json(ErrorResp { error: self.to_string(), request_id: logging::request_id() })
     }
 }
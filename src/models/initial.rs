@@ -1,9 +1,42 @@
-use std::env::var as env_var;
-use std::time::Duration;
+use crate::config::{self, ConfigReport, RawConfig, Reader};
+use crate::logging::LogFormat;
+use crate::database::{pool::Recycling, retry::RetryPolicy, tls::{make_tls_connect, SslMode}};
+use crate::utils::parse_scope_override;
+use crate::invalidation;
+use crate::middleware::cors::{parse_scope_origins, CorsOrigins, OriginPattern};
+use crate::rate_limit::{KeyKind, Policies, Policy};
+use actix_web::http::header::HttpDate;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+
+pub struct PgTlsSettings {
+    pub mode: SslMode,
+    pub root_cert: Option<PathBuf>,     // CA bundle
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+
+pub struct ReplicaSettings {
+    pub urls: Vec<(String, u32)>,       // Connection string and weight
+    pub max_lag: Duration,
+    pub check_interval: Duration,
+    pub read_your_writes: Duration,     // Zero disables it
+}
+
+
+pub struct BreakerSettings {
+    pub threshold: u32,                 // Zero disables the breaker
+    pub cooldown: Duration,
+}
 
 
 pub struct PgSettings {
     pub url: String,
+    pub tls: PgTlsSettings,
+    pub replicas: ReplicaSettings,
+    pub statement_timeout: Duration,    // Zero disables it
+    pub request_timeout: Duration,      // Zero disables it
     pub conn_timeout: u64,
     pub max_pool_size: usize,
     pub wait_timeout: u64,
@@ -11,19 +44,138 @@ pub struct PgSettings {
     pub recycle_timeout: u64,
     pub warm_pool: bool,
     pub warm_pool_size: usize,
+    pub min_idle: usize,
+    pub recycling: Recycling,
+    pub retry: RetryPolicy,
+    pub breaker: BreakerSettings,
+    pub check_queries: bool,
 }
 
 
 pub struct MokaSettings {
     pub cache_size: u64,
     pub expiration_time: Duration,
+    pub invalidation_channel: Option<String>,   // None disables the invalidation across instances
+}
+
+
+pub struct JobSettings {
+    pub run_workers: bool,              // In the API process, the `worker` subcommand always runs them
+    pub concurrency: usize,
+    pub max_queued: u32,                // Enqueueing fails past it
+    pub poll_interval: Duration,
+    pub visibility_timeout: Duration,
+    pub timeout: Duration,              // Per attempt
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub retention: Duration,            // Zero keeps the finished jobs
+}
+
+
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+
+pub struct ServerSettings {
+    pub workers: usize,
+    pub listen_addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub unix_socket: Option<PathBuf>,
+    pub backlog: u32,
+    pub max_connections: usize,
+    pub keep_alive: u64,                // Seconds, 0 disables keep-alive
+    pub client_request_timeout: u64,    // Milliseconds, 0 disables the timeout
+    pub client_disconnect_timeout: u64, // Milliseconds, 0 disables the timeout
+    pub shutdown_delay: Duration,
+    pub shutdown_timeout: Duration,
+    pub json_limit: usize,
+    pub payload_limit: usize,
+    pub http2: bool,
+    pub tls: Option<TlsSettings>,
+}
+
+
+#[derive(Clone)]
+pub struct CorsSettings {
+    pub origins: CorsOrigins,           // Reloadable
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,   // `*` allows any header
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: usize,                 // Seconds
+}
+
+
+pub struct SecurityHeadersSettings {
+    pub hsts: Option<String>,               // None when the header is off
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub opener_policy: Option<String>,
+    pub resource_policy: Option<String>,
+    pub embedder_policy: Option<String>,
+    pub csp: Option<String>,
+    pub csp_scopes: Vec<(String, Option<String>)>,
+}
+
+
+pub struct TracingSettings {
+    pub otlp_endpoint: Option<String>,  // Tracing is disabled when unset
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RateLimitBackendKind {
+    Memory,
+    Postgres,
+}
+
+
+pub struct RateLimitSettings {
+    pub backend: RateLimitBackendKind,
+    pub trust_forwarded: bool,
+    pub policies: Policies,
+}
+
+
+pub struct HealthSettings {
+    pub check_timeout: Duration,        // Per component check
+    pub cache_ttl: Duration,            // How long a health report is reused
+}
+
+
+#[derive(Clone)]
+pub struct ApiSettings {
+    pub default_version: u8,             // Version served by unversioned paths
+    pub version_negotiation: bool,       // Resolve unversioned paths from the `Accept` header
+    pub legacy_deprecation: HttpDate,       // When unversioned paths were deprecated
+    pub legacy_sunset: Option<HttpDate>,    // When unversioned paths stop working
+    pub v1_deprecation: Option<HttpDate>,   // If set (or the sunset), `/v1` is deprecated in favour of `/v2`
+    pub v1_sunset: Option<HttpDate>,
+    pub admin_token: Option<String>,     // Unset disables the admin endpoints
 }
 
 
 pub struct AppSettings {
     pub pg_settings: PgSettings,
     pub cache_settings: MokaSettings,
+    pub job_settings: JobSettings,
+    pub api_settings: ApiSettings,
+    pub server_settings: ServerSettings,
+    pub health_settings: HealthSettings,
+    pub tracing_settings: TracingSettings,
+    pub cors_settings: CorsSettings,
+    pub security_headers_settings: SecurityHeadersSettings,
+    pub rate_limit_settings: RateLimitSettings,
     pub enable_logging: bool,
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    pub access_log_headers: bool,
+    pub reload_interval: Duration,
 }
 
 
@@ -31,93 +183,496 @@ pub struct AppSettings {
 
 
 impl PgSettings {
-    fn from_env() -> Self {
-        let url = env_var("POSTGRES_DB_URL").expect("POSTGRES_DB_URL must be set");
-        let conn_timeout = env_var("PG_CONN_TIMEOUT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_CONN_TIMEOUT must be a positive integer of type u64");
-        let max_pool_size = env_var("PG_POOL_MAX_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_POOL_MAX_SIZE must be a positive integer of type usize");
-        let wait_timeout = env_var("PG_POOL_WAIT_TIMEOUT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_POOL_WAIT_TIMEOUT must be a positive integer of type u64");
-        let new_connection_timeout = env_var("PG_POOL_NEW_CONNECTION_TIMEOUT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_POOL_NEW_CONNECTION_TIMEOUT must be a positive integer of type u64");
-        let recycle_timeout = env_var("PG_POOL_RECYCLE_TIMEOUT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_POOL_RECYCLE_TIMEOUT must be a positive integer of type u64");
-        let warm_pool = env_var("PG_POOL_WARM_POOL").expect("PG_POOL_WARM_POOL must be set as true or false");
-        let warm_pool = match warm_pool.to_lowercase().as_str() {
-            "true" => true,
-            "false" => false,
-            _ => panic!("PG_POOL_WARM_POOL must be set as true or false"),
-        };
-        let warm_pool_size = env_var("PG_POOL_WARM_POOL_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("PG_POOL_WARM_POOL_SIZE must be a positive integer of type usize");
+    fn from_config(r: &mut Reader) -> Self {
+        let url: String = r.value("POSTGRES_DB_URL");
+        let settings = PgSettings {
+            tls: PgTlsSettings::from_config(r, &url),
+            replicas: ReplicaSettings::from_config(r),
+            url,
+            statement_timeout: Duration::from_millis(r.value("PG_STATEMENT_TIMEOUT")),
+            request_timeout: Duration::from_millis(r.value("PG_REQUEST_TIMEOUT")),
+            conn_timeout: r.value("PG_CONN_TIMEOUT"),
+            max_pool_size: r.value("PG_POOL_MAX_SIZE"),
+            wait_timeout: r.value("PG_POOL_WAIT_TIMEOUT"),
+            new_connection_timeout: r.value("PG_POOL_NEW_CONNECTION_TIMEOUT"),
+            recycle_timeout: r.value("PG_POOL_RECYCLE_TIMEOUT"),
+            warm_pool: r.flag("PG_POOL_WARM_POOL"),
+            warm_pool_size: r.value("PG_POOL_WARM_POOL_SIZE"),
+            min_idle: r.value("PG_POOL_MIN_IDLE"),
+            recycling: r.value("PG_POOL_RECYCLING_METHOD"),
+            retry: RetryPolicy {
+                attempts: r.value("PG_RETRY_ATTEMPTS"),
+                backoff: Duration::from_millis(r.value("PG_RETRY_BACKOFF")),
+            },
+            breaker: BreakerSettings {
+                threshold: r.value("PG_BREAKER_THRESHOLD"),
+                cooldown: Duration::from_millis(r.value("PG_BREAKER_COOLDOWN")),
+            },
+            check_queries: r.flag("PG_CHECK_QUERIES"),
+        };
+
+        r.check(
+            settings.url.is_empty() || settings.url.parse::<tokio_postgres::Config>().is_ok(),
+            "POSTGRES_DB_URL is not a valid PostgreSQL connection string",
+        );
 
         // Warm pool size can not go above 128 (if warm pool is enabled)
-        if warm_pool_size > max_pool_size {
-            panic!("PG_POOL_WARM_POOL_SIZE must be at most PG_POOL_MAX_SIZE, it can not go more than {}", max_pool_size);
+        r.check(
+            settings.warm_pool_size <= settings.max_pool_size,
+            format!("PG_POOL_WARM_POOL_SIZE must be at most PG_POOL_MAX_SIZE, it can not go more than {}", settings.max_pool_size),
+        );
+        r.check(
+            !settings.warm_pool || settings.warm_pool_size <= 128,
+            "PG_POOL_WARM_POOL_SIZE must be at most 128, and the optimal size is 64",
+        );
+        r.check(
+            settings.min_idle <= settings.max_pool_size,
+            format!("PG_POOL_MIN_IDLE must be at most PG_POOL_MAX_SIZE ({})", settings.max_pool_size),
+        );
+        r.check(
+            settings.breaker.threshold == 0 || !settings.breaker.cooldown.is_zero(),
+            "PG_BREAKER_COOLDOWN must be at least 1 when the circuit breaker is enabled",
+        );
+
+        settings
+    }
+}
+
+
+impl PgTlsSettings {
+    fn from_config(r: &mut Reader, url: &str) -> Self {
+        // The URL's sslmode is used when PG_SSL_MODE is not set
+        let url_mode = match url.parse::<tokio_postgres::Config>().map(|cfg| cfg.get_ssl_mode()) {
+            Ok(tokio_postgres::config::SslMode::Disable) => SslMode::Disable,
+            Ok(tokio_postgres::config::SslMode::Require) => SslMode::Require,
+            _ => SslMode::Prefer,
+        };
+        let settings = PgTlsSettings {
+            mode: r.optional("PG_SSL_MODE").unwrap_or(url_mode),
+            root_cert: r.optional("PG_SSL_ROOT_CERT"),
+            client_cert: r.optional("PG_SSL_CERT"),
+            client_key: r.optional("PG_SSL_KEY"),
+        };
+
+        for (env, path) in [("PG_SSL_ROOT_CERT", &settings.root_cert), ("PG_SSL_CERT", &settings.client_cert), ("PG_SSL_KEY", &settings.client_key)] {
+            if let Some(path) = path {
+                r.check(path.is_file(), format!("{}: {} is not a readable file", env, path.display()));
+            }
         }
-        if warm_pool && warm_pool_size > 128 {
-            panic!("PG_POOL_WARM_POOL_SIZE must be at most 128, and the optimal size is 64");
+        r.check(
+            settings.client_cert.is_some() == settings.client_key.is_some(),
+            "PG_SSL_CERT and PG_SSL_KEY must be set together",
+        );
+        r.check(
+            !matches!(settings.mode, SslMode::VerifyCa | SslMode::VerifyFull) || settings.root_cert.is_some(),
+            format!("PG_SSL_MODE={} needs the CA bundle in PG_SSL_ROOT_CERT", settings.mode),
+        );
+        // libpq ignores the bundle with these modes, better to refuse than to look verified
+        r.check(
+            !matches!(settings.mode, SslMode::Disable | SslMode::Prefer) || settings.root_cert.is_none(),
+            format!("PG_SSL_MODE={} does not check the certificate, use require, verify-ca or verify-full with PG_SSL_ROOT_CERT", settings.mode),
+        );
+
+        // Parse the PEM files now, a bad bundle or a key of another certificate is a configuration error
+        let files = [&settings.root_cert, &settings.client_cert, &settings.client_key];
+        if files.iter().all(|path| path.as_ref().is_none_or(|path| path.is_file()))
+            && let Err(e) = make_tls_connect(&settings)
+        {
+            r.check(false, format!("Postgres TLS: {}", e));
         }
 
-        PgSettings {
-            url,
-            conn_timeout,
-            max_pool_size,
-            wait_timeout,
-            new_connection_timeout,
-            recycle_timeout,
-            warm_pool,
-            warm_pool_size,
+        settings
+    }
+}
+
+
+impl ReplicaSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let mut urls = Vec::new();
+        for (i, entry) in r.list("PG_REPLICA_URLS").into_iter().enumerate() {
+            let (url, weight) = match entry.rsplit_once('|') {
+                Some((url, weight)) => (url.to_string(), weight.trim().parse().unwrap_or(0)),
+                None => (entry, 1),
+            };
+            // Named by position (from 0) like the pool of a replica without a host, the URL may hold a password
+            r.check(
+                url.parse::<tokio_postgres::Config>().is_ok(),
+                format!("PG_REPLICA_URLS: the URL of replica {} is not a valid PostgreSQL connection string", i),
+            );
+            r.check(weight > 0, format!("PG_REPLICA_URLS: the weight of replica {} must be a number of at least 1", i));
+            urls.push((url, weight));
         }
+
+        let settings = ReplicaSettings {
+            urls,
+            max_lag: Duration::from_millis(r.value("PG_REPLICA_MAX_LAG")),
+            check_interval: Duration::from_millis(r.value("PG_REPLICA_CHECK_INTERVAL")),
+            read_your_writes: Duration::from_millis(r.value("PG_READ_YOUR_WRITES")),
+        };
+
+        r.check(!settings.check_interval.is_zero(), "PG_REPLICA_CHECK_INTERVAL must be at least 1");
+
+        settings
     }
 }
 
 
 impl MokaSettings {
-    fn from_env() -> Self {
-        let cache_size = env_var("CACHE_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("CACHE_SIZE must be a positive integer of type u64");
-        let expiration_time = env_var("CACHE_EXPIRATION_TIME")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .expect("CACHE_EXPIRATION_TIME must be a positive integer of type u64");
+    fn from_config(r: &mut Reader) -> Self {
+        let channel: String = r.value("CACHE_INVALIDATION_CHANNEL");
+        r.check(
+            channel.is_empty() || invalidation::is_valid_channel(&channel),
+            "CACHE_INVALIDATION_CHANNEL must be lowercase letters, digits and _ (at most 63), not starting with a digit",
+        );
 
         MokaSettings {
-            cache_size,
-            expiration_time: Duration::from_secs(expiration_time),
+            cache_size: r.value("CACHE_SIZE"),
+            expiration_time: Duration::from_secs(r.value("CACHE_EXPIRATION_TIME")),
+            invalidation_channel: (!channel.is_empty()).then_some(channel),
         }
     }
 }
 
 
-impl AppSettings {
-    pub fn from_env() -> Self {
-        let enable_logging = env_var("ENABLE_LOGGING").expect("ENABLE_LOGGING must be set as true or false");
-        let enable_logging = match enable_logging.to_lowercase().as_str() {
-            "true" => true,
-            "false" => false,
-            _ => panic!("ENABLE_LOGGING must be set as true or false"),
-        };
-
-        AppSettings {
-            pg_settings: PgSettings::from_env(),
-            cache_settings: MokaSettings::from_env(),
-            enable_logging,
+impl JobSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let settings = JobSettings {
+            run_workers: r.flag("JOBS_RUN_WORKERS"),
+            concurrency: r.value("JOBS_CONCURRENCY"),
+            max_queued: r.value("JOBS_MAX_QUEUED"),
+            poll_interval: Duration::from_millis(r.value("JOBS_POLL_INTERVAL")),
+            visibility_timeout: Duration::from_millis(r.value("JOBS_VISIBILITY_TIMEOUT")),
+            timeout: Duration::from_millis(r.value("JOBS_TIMEOUT")),
+            max_attempts: r.value("JOBS_MAX_ATTEMPTS"),
+            backoff: Duration::from_millis(r.value("JOBS_BACKOFF")),
+            retention: Duration::from_secs(r.value("JOBS_RETENTION")),
+        };
+
+        for (env, value) in [
+            ("JOBS_CONCURRENCY", settings.concurrency),
+            ("JOBS_MAX_QUEUED", settings.max_queued as usize),
+            ("JOBS_POLL_INTERVAL", settings.poll_interval.as_millis() as usize),
+            ("JOBS_TIMEOUT", settings.timeout.as_millis() as usize),
+            ("JOBS_MAX_ATTEMPTS", settings.max_attempts as usize),
+        ] {
+            r.check(value > 0, format!("{} must be at least 1", env));
+        }
+        // A job still running must not be claimed by another worker
+        r.check(
+            settings.visibility_timeout > settings.timeout,
+            "JOBS_VISIBILITY_TIMEOUT must be longer than JOBS_TIMEOUT",
+        );
+
+        settings
+    }
+}
+
+
+impl ServerSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let mut listen_addresses = Vec::new();
+        for address in r.list("API_LISTEN_ADDRESSES") {
+            match address.parse() {
+                Ok(ip) => listen_addresses.push(ip),
+                Err(_) => r.check(false, format!("API_LISTEN_ADDRESSES: '{}' is not an IP address", address)),
+            }
+        }
+
+        let cert_path: Option<PathBuf> = r.optional("API_TLS_CERT_PATH");
+        let key_path: Option<PathBuf> = r.optional("API_TLS_KEY_PATH");
+        let tls = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                r.check(cert_path.is_file(), format!("API_TLS_CERT_PATH: {} is not a readable file", cert_path.display()));
+                r.check(key_path.is_file(), format!("API_TLS_KEY_PATH: {} is not a readable file", key_path.display()));
+                Some(TlsSettings { cert_path, key_path })
+            }
+            (None, None) => None,
+            _ => {
+                r.check(false, "API_TLS_CERT_PATH and API_TLS_KEY_PATH must be set together");
+                None
+            }
+        };
+
+        let settings = ServerSettings {
+            workers: r.value("API_WORKERS_COUNT"),
+            listen_addresses,
+            port: r.value("API_PORT"),
+            unix_socket: r.optional("API_UNIX_SOCKET"),
+            backlog: r.value("API_BACKLOG"),
+            max_connections: r.value("API_MAX_CONNECTIONS"),
+            keep_alive: r.value("API_KEEP_ALIVE"),
+            client_request_timeout: r.value("API_CLIENT_REQUEST_TIMEOUT"),
+            client_disconnect_timeout: r.value("API_CLIENT_DISCONNECT_TIMEOUT"),
+            shutdown_delay: Duration::from_secs(r.value("API_SHUTDOWN_DELAY")),
+            shutdown_timeout: Duration::from_secs(r.value("API_SHUTDOWN_TIMEOUT")),
+            json_limit: r.value("API_JSON_LIMIT"),
+            payload_limit: r.value("API_PAYLOAD_LIMIT"),
+            http2: r.flag("API_HTTP2"),
+            tls,
+        };
+
+        r.check(settings.workers > 0, "API_WORKERS_COUNT must be at least 1");
+        r.check(
+            !settings.listen_addresses.is_empty() || settings.unix_socket.is_some(),
+            "nothing to listen on, set API_LISTEN_ADDRESSES or API_UNIX_SOCKET",
+        );
+        r.check(
+            cfg!(unix) || settings.unix_socket.is_none(),
+            "API_UNIX_SOCKET is only supported on Unix",
+        );
+
+        settings
+    }
+}
+
+
+impl CorsSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let mut origins = CorsOrigins::default();
+        for origin in r.list("API_CORS_ALLOWED_ORIGINS") {
+            match origin.parse() {
+                Ok(pattern) => origins.default.push(pattern),
+                Err(e) => r.check(false, format!("API_CORS_ALLOWED_ORIGINS: {}", e)),
+            }
+        }
+        for scope in r.list("API_CORS_SCOPE_ORIGINS") {
+            match parse_scope_origins(&scope) {
+                Ok(scope) => origins.scopes.push(scope),
+                Err(e) => r.check(false, format!("API_CORS_SCOPE_ORIGINS: {}", e)),
+            }
+        }
+
+        let settings = CorsSettings {
+            origins,
+            allowed_methods: r.list("API_CORS_ALLOWED_METHODS").into_iter().map(|m| m.to_ascii_uppercase()).collect(),
+            allowed_headers: r.list("API_CORS_ALLOWED_HEADERS").into_iter().map(|h| h.to_ascii_lowercase()).collect(),
+            exposed_headers: r.list("API_CORS_EXPOSED_HEADERS").into_iter().map(|h| h.to_ascii_lowercase()).collect(),
+            allow_credentials: r.flag("API_CORS_ALLOW_CREDENTIALS"),
+            max_age: r.value("API_CORS_MAX_AGE"),
+        };
+
+        for method in &settings.allowed_methods {
+            r.check(
+                method.parse::<actix_web::http::Method>().is_ok(),
+                format!("API_CORS_ALLOWED_METHODS: '{}' is not an HTTP method", method),
+            );
+        }
+        for (env, headers) in [("API_CORS_ALLOWED_HEADERS", &settings.allowed_headers), ("API_CORS_EXPOSED_HEADERS", &settings.exposed_headers)] {
+            for header in headers.iter().filter(|h| *h != "*") {
+                r.check(
+                    header.parse::<actix_web::http::header::HeaderName>().is_ok(),
+                    format!("{}: '{}' is not a header name", env, header),
+                );
+            }
+        }
+        // Any site could act with the user's session cookie
+        r.check(
+            !(settings.allow_credentials && settings.origins.default.contains(&OriginPattern::Any)),
+            "API_CORS_ALLOWED_ORIGINS can not be * while API_CORS_ALLOW_CREDENTIALS is on, list the origins or set a scope override",
+        );
+
+        settings
+    }
+}
+
+
+impl SecurityHeadersSettings {
+    fn header(r: &mut Reader, env: &str, value: &str) -> Option<String> {
+        if value.is_empty() || value == "off" {
+            return None;
+        }
+        r.check(
+            actix_web::http::header::HeaderValue::from_str(value).is_ok(),
+            format!("{}: '{}' is not a valid header value", env, value),
+        );
+        Some(value.to_string())
+    }
+
+    fn from_config(r: &mut Reader) -> Self {
+        let mut header = |env| {
+            let value: String = r.value(env);
+            Self::header(r, env, value.trim())
+        };
+        let mut settings = SecurityHeadersSettings {
+            hsts: header("API_SECURITY_HSTS"),
+            frame_options: header("API_SECURITY_FRAME_OPTIONS"),
+            referrer_policy: header("API_SECURITY_REFERRER_POLICY"),
+            permissions_policy: header("API_SECURITY_PERMISSIONS_POLICY"),
+            opener_policy: header("API_SECURITY_COOP"),
+            resource_policy: header("API_SECURITY_CORP"),
+            embedder_policy: header("API_SECURITY_COEP"),
+            csp: header("API_SECURITY_CSP"),
+            csp_scopes: Vec::new(),
+        };
+
+        for scope in r.list("API_SECURITY_CSP_SCOPES") {
+            match parse_scope_override(&scope) {
+                Ok((scope, policy)) => {
+                    let policy = Self::header(r, "API_SECURITY_CSP_SCOPES", policy);
+                    settings.csp_scopes.push((scope, policy));
+                }
+                Err(e) => r.check(false, format!("API_SECURITY_CSP_SCOPES: {}", e)),
+            }
+        }
+
+        settings
+    }
+}
+
+
+impl TracingSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let settings = TracingSettings {
+            otlp_endpoint: r.optional("OTEL_EXPORTER_OTLP_ENDPOINT"),
+            service_name: r.value("OTEL_SERVICE_NAME"),
+            sample_ratio: r.value("OTEL_TRACES_SAMPLER_ARG"),
+        };
+
+        if let Some(endpoint) = &settings.otlp_endpoint {
+            r.check(
+                endpoint.starts_with("http://") || endpoint.starts_with("https://"),
+                format!("OTEL_EXPORTER_OTLP_ENDPOINT: '{}' is not an http(s) URL", endpoint),
+            );
+        }
+        r.check(
+            (0.0..=1.0).contains(&settings.sample_ratio),
+            "OTEL_TRACES_SAMPLER_ARG must be between 0.0 and 1.0",
+        );
+
+        settings
+    }
+}
+
+
+impl RateLimitSettings {
+    fn policy(r: &mut Reader, env: &str) -> Option<Policy> {
+        let value: String = r.value(env);
+        Policy::parse_setting(&value).unwrap_or_else(|e| {
+            r.check(false, format!("{}: {}", env, e));
+            None
+        })
+    }
+
+    fn from_config(r: &mut Reader) -> Self {
+        let backend = match r.value::<String>("API_RATE_LIMIT_BACKEND").as_str() {
+            "memory" => RateLimitBackendKind::Memory,
+            "postgres" => RateLimitBackendKind::Postgres,
+            other => {
+                r.check(false, format!("API_RATE_LIMIT_BACKEND: '{}' is not memory or postgres", other));
+                RateLimitBackendKind::Memory
+            }
+        };
+
+        let settings = RateLimitSettings {
+            backend,
+            trust_forwarded: r.flag("API_RATE_LIMIT_TRUST_FORWARDED"),
+            policies: Policies {
+                default: Self::policy(r, "API_RATE_LIMIT_DEFAULT"),
+                session_create: Self::policy(r, "API_RATE_LIMIT_SESSION_CREATE"),
+                note_create: Self::policy(r, "API_RATE_LIMIT_NOTE_CREATE"),
+            },
+        };
+
+        // The default limit runs before the authentication, a user key would always be the address
+        r.check(
+            settings.policies.default.as_ref().is_none_or(|policy| policy.key != KeyKind::User),
+            "API_RATE_LIMIT_DEFAULT can not be keyed on user, every request is counted before its session is read (use ip)",
+        );
+
+        settings
+    }
+}
+
+
+impl HealthSettings {
+    fn from_config(r: &mut Reader) -> Self {
+        let settings = HealthSettings {
+            check_timeout: Duration::from_millis(r.value("API_HEALTH_CHECK_TIMEOUT")),
+            cache_ttl: Duration::from_millis(r.value("API_HEALTH_CACHE_TTL")),
+        };
+
+        r.check(!settings.check_timeout.is_zero(), "API_HEALTH_CHECK_TIMEOUT must be at least 1");
+
+        settings
+    }
+}
+
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        ApiSettings {
+            default_version: 1,
+            version_negotiation: false,
+            legacy_deprecation: Self::default_legacy_deprecation(),
+            legacy_sunset: None,
+            v1_deprecation: None,
+            v1_sunset: None,
+            admin_token: None,
         }
     }
 }
+
+
+impl ApiSettings {
+    /// API versions mounted by the router (`/v1`, `/v2`)
+    pub const SUPPORTED_VERSIONS: [u8; 2] = [1, 2];
+
+    // The registry default of `API_LEGACY_DEPRECATION`, the only place it is written
+    fn default_legacy_deprecation() -> HttpDate {
+        config::default_value("API_LEGACY_DEPRECATION")
+            .and_then(|date| date.parse().ok())
+            .expect("the registry default of API_LEGACY_DEPRECATION is an HTTP date")
+    }
+
+    fn from_config(r: &mut Reader) -> Self {
+        let settings = ApiSettings {
+            default_version: r.value::<String>("API_DEFAULT_VERSION").trim_start_matches(['v', 'V']).parse().unwrap_or(0),
+            version_negotiation: r.flag("API_VERSION_NEGOTIATION"),
+            legacy_deprecation: r.optional("API_LEGACY_DEPRECATION").unwrap_or_else(Self::default_legacy_deprecation),
+            legacy_sunset: r.optional("API_LEGACY_SUNSET"),
+            v1_deprecation: r.optional("API_V1_DEPRECATION"),
+            v1_sunset: r.optional("API_V1_SUNSET"),
+            admin_token: r.optional::<String>("API_ADMIN_TOKEN").filter(|token| !token.is_empty()),
+        };
+
+        r.check(
+            Self::SUPPORTED_VERSIONS.contains(&settings.default_version),
+            format!("API_DEFAULT_VERSION must be one of {:?}", Self::SUPPORTED_VERSIONS),
+        );
+        // Anything shorter can be guessed
+        r.check(
+            settings.admin_token.as_ref().is_none_or(|token| token.len() >= 16),
+            "API_ADMIN_TOKEN must be at least 16 characters",
+        );
+
+        settings
+    }
+}
+
+
+impl AppSettings {
+    /// Build the settings from the merged config layers, every invalid value is reported at once
+    pub fn from_config(raw: &RawConfig) -> Result<Self, ConfigReport> {
+        let mut r = raw.reader();
+
+        let settings = AppSettings {
+            pg_settings: PgSettings::from_config(&mut r),
+            cache_settings: MokaSettings::from_config(&mut r),
+            job_settings: JobSettings::from_config(&mut r),
+            api_settings: ApiSettings::from_config(&mut r),
+            server_settings: ServerSettings::from_config(&mut r),
+            health_settings: HealthSettings::from_config(&mut r),
+            tracing_settings: TracingSettings::from_config(&mut r),
+            cors_settings: CorsSettings::from_config(&mut r),
+            security_headers_settings: SecurityHeadersSettings::from_config(&mut r),
+            rate_limit_settings: RateLimitSettings::from_config(&mut r),
+            enable_logging: r.flag("ENABLE_LOGGING"),
+            log_filter: r.value("RUST_LOG"),
+            log_format: r.value("APP_LOG_FORMAT"),
+            access_log_headers: r.flag("APP_ACCESS_LOG_HEADERS"),
+            reload_interval: Duration::from_secs(r.value("APP_RELOAD_INTERVAL")),
+        };
+
+        r.finish(settings)
+    }
+}
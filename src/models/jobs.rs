@@ -0,0 +1,274 @@
+use crate::database::query::{Column, FromRow};
+use actix_web::http::header::HttpDate;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{types::{FromSql, Type}, Error, Row};
+use std::{fmt, str::FromStr, time::SystemTime};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+
+
+/// Where a job is in its life: queued (maybe scheduled later), claimed by a worker, then finished one way or another
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Dead,       // Failed for good, can be retried by an admin
+    Cancelled,
+}
+
+
+/// Storage model, mirrors a row of the `jobs` table
+pub struct JobRow {
+    pub id: Uuid,
+    pub kind: String,
+    pub payload: Value,
+    pub status: JobStatus,
+    pub priority: i32,
+    pub run_at: SystemTime,
+    pub dedupe_key: Option<String>,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    pub locked_by: Option<String>,
+    pub created_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+}
+
+
+/// A job to insert, `dedupe_key` skips it while a job with the same key is queued or running
+pub struct NewJob<'a> {
+    pub kind: &'a str,
+    pub payload: &'a Value,
+    pub priority: i32,
+    pub run_at: SystemTime,
+    pub dedupe_key: Option<&'a str>,
+    pub max_attempts: i32,
+}
+
+
+/// What inserting a job did
+pub enum Inserted {
+    Created(Uuid),
+    Duplicate(Uuid),    // The job with the same dedupe key, already waiting
+    Full,               // Too many jobs queued, nothing inserted
+    Conflict,           // The job with the same dedupe key kept finishing between the conflict and the lookup
+}
+
+
+/// Jobs waiting (ready to run or scheduled later), running and dead
+#[derive(Serialize)]
+pub struct JobCounts {
+    pub ready: i64,
+    pub scheduled: i64,
+    pub running: i64,
+    pub dead: i64,
+}
+
+
+/// A job a worker claimed, `attempts` counts this attempt and identifies the claim
+#[derive(Clone)]
+pub struct ClaimedRow {
+    pub id: Uuid,
+    pub kind: String,
+    pub payload: Value,
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+
+/// Query parameters of the job list, the newest jobs first
+#[derive(Deserialize, IntoParams)]
+#[serde(deny_unknown_fields)]
+#[into_params(parameter_in = Query)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub kind: Option<String>,
+    /// At most this many jobs (50 by default, up to 500)
+    pub limit: Option<i64>,
+}
+
+
+/// What the admin API returns for a job
+#[derive(Serialize, ToSchema)]
+pub struct JobResponse {
+    pub id: String,
+    pub kind: String,
+    pub payload: Value,
+    pub status: JobStatus,
+    pub priority: i32,
+    pub run_at: String,
+    pub dedupe_key: Option<String>,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    /// Worker running the job
+    pub locked_by: Option<String>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+
+fn http_date(time: SystemTime) -> String {
+    HttpDate::from(time).to_string()
+}
+
+
+// ------- Implementations ------- //
+
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Dead => "dead",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "dead" => Ok(JobStatus::Dead),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            _ => Err(format!("unknown job status '{}'", s)),
+        }
+    }
+}
+
+
+// Stored as text, the table only accepts the known statuses (CHECK constraint)
+impl<'a> FromSql<'a> for JobStatus {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(<&str>::from_sql(ty, raw)?.parse()?)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+impl FromRow for JobRow {
+    const COLUMNS: &'static [Column] = &[
+        Column::new::<Uuid>("id"),
+        Column::new::<String>("kind"),
+        Column::new::<Value>("payload"),
+        Column::new::<JobStatus>("status"),
+        Column::new::<i32>("priority"),
+        Column::new::<SystemTime>("run_at"),
+        Column::new::<Option<String>>("dedupe_key"),
+        Column::new::<i32>("attempts"),
+        Column::new::<i32>("max_attempts"),
+        Column::new::<Option<String>>("last_error"),
+        Column::new::<Option<String>>("locked_by"),
+        Column::new::<SystemTime>("created_at"),
+        Column::new::<Option<SystemTime>>("finished_at"),
+    ];
+
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(JobRow {
+            id: row.try_get("id")?,
+            kind: row.try_get("kind")?,
+            payload: row.try_get("payload")?,
+            status: row.try_get("status")?,
+            priority: row.try_get("priority")?,
+            run_at: row.try_get("run_at")?,
+            dedupe_key: row.try_get("dedupe_key")?,
+            attempts: row.try_get("attempts")?,
+            max_attempts: row.try_get("max_attempts")?,
+            last_error: row.try_get("last_error")?,
+            locked_by: row.try_get("locked_by")?,
+            created_at: row.try_get("created_at")?,
+            finished_at: row.try_get("finished_at")?,
+        })
+    }
+}
+
+
+impl FromRow for JobCounts {
+    const COLUMNS: &'static [Column] = &[
+        Column::new::<i64>("ready"),
+        Column::new::<i64>("scheduled"),
+        Column::new::<i64>("running"),
+        Column::new::<i64>("dead"),
+    ];
+
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(JobCounts {
+            ready: row.try_get("ready")?,
+            scheduled: row.try_get("scheduled")?,
+            running: row.try_get("running")?,
+            dead: row.try_get("dead")?,
+        })
+    }
+}
+
+
+impl FromRow for ClaimedRow {
+    const COLUMNS: &'static [Column] = &[
+        Column::new::<Uuid>("id"),
+        Column::new::<String>("kind"),
+        Column::new::<Value>("payload"),
+        Column::new::<i32>("attempts"),
+        Column::new::<i32>("max_attempts"),
+    ];
+
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(ClaimedRow {
+            id: row.try_get("id")?,
+            kind: row.try_get("kind")?,
+            payload: row.try_get("payload")?,
+            attempts: row.try_get("attempts")?,
+            max_attempts: row.try_get("max_attempts")?,
+        })
+    }
+}
+
+
+// Mapping layer: the DB schema can change without breaking the API shape
+impl From<JobRow> for JobResponse {
+    fn from(row: JobRow) -> Self {
+        JobResponse {
+            id: row.id.to_string(),
+            kind: row.kind,
+            payload: row.payload,
+            status: row.status,
+            priority: row.priority,
+            run_at: http_date(row.run_at),
+            dedupe_key: row.dedupe_key,
+            attempts: row.attempts,
+            max_attempts: row.max_attempts,
+            last_error: row.last_error,
+            locked_by: row.locked_by,
+            created_at: http_date(row.created_at),
+            finished_at: row.finished_at.map(http_date),
+        }
+    }
+}
+
+
+impl JobResponse {
+    pub fn from_rows(rows: Vec<JobRow>) -> Vec<Self> {
+        rows.into_iter().map(JobResponse::from).collect()
+    }
+}
@@ -1,4 +1,5 @@
 pub mod initial;
 pub mod errors;
+pub mod jobs;
 pub mod notes;
 pub mod user;
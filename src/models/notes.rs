@@ -1,12 +1,41 @@
 use serde::{Deserialize, Serialize};
-use tokio_postgres::row::Row;
+use crate::database::query::{Column, FromRow};
+use tokio_postgres::{Error, Row};
+use utoipa::ToSchema;
 use std::fmt;
 
 
 
-#[derive(Serialize, Deserialize)]
-pub struct Notes {
-    pub id: Option<i32>,    // If user wants to create we can use same struct
+/// Storage model, mirrors a row of the `notes` table (never leaves the DB layer as-is)
+pub struct NoteRow {
+    pub id: i32,
+    pub title: String,
+    pub content: String,
+}
+
+
+/// Request body to create a new note, the `id` is always assigned by the DB
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CreateNote {
+    pub title: String,
+    pub content: String,
+}
+
+
+/// Request body to update an existing note, only the given fields are changed
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateNote {
+    pub title: Option<String>,
+    pub content: Option<String>,
+}
+
+
+/// What the API returns for a note
+#[derive(Serialize, ToSchema)]
+pub struct NoteResponse {
+    pub id: i32,
     pub title: String,
     pub content: String,
 }
@@ -15,27 +44,55 @@ pub struct Notes {
 // ------- Implementations ------- //
 
 
-impl fmt::Display for Notes {
+impl fmt::Display for CreateNote {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Note Title: {}, Content: {}", self.title, self.content)
     }
 }
 
 
-impl From<Row> for Notes {
-    fn from(row: Row) -> Self {
-        // Use explicit type for id to handle SQL NULLs
-        let id: Option<i32> = row.get::<_, Option<i32>>("id");
-        let title: String = row.get("title");
-        let content: String = row.get("content");
+impl fmt::Display for NoteRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Note ID: {}, Title: {}, Content: {}", self.id, self.title, self.content)
+    }
+}
+
+
+impl FromRow for NoteRow {
+    const COLUMNS: &'static [Column] = &[Column::new::<i32>("id"), Column::new::<String>("title"), Column::new::<String>("content")];
+
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(NoteRow {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            content: row.try_get("content")?,
+        })
+    }
+}
+
+
+// Mapping layer: the DB schema can change without breaking the API shape
+impl From<NoteRow> for NoteResponse {
+    fn from(row: NoteRow) -> Self {
+        NoteResponse {
+            id: row.id,
+            title: row.title,
+            content: row.content,
+        }
+    }
+}
+
 
-        Notes { id, title, content }
+impl NoteResponse {
+    pub fn from_rows(rows: Vec<NoteRow>) -> Vec<Self> {
+        rows.into_iter().map(NoteResponse::from).collect()
     }
 }
 
 
-impl Notes {
-    pub fn from_rows(rows: Vec<Row>) -> Vec<Self> {
-        rows.into_iter().map(Notes::from).collect()
+impl UpdateNote {
+    /// True when the request does not change anything
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.content.is_none()
     }
 }
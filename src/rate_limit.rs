@@ -0,0 +1,369 @@
+use moka::{future::Cache, ops::compute::{CompResult, Op}, Expiry};
+use std::{fmt, future::Future, pin::Pin, str::FromStr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use deadpool_postgres::Pool as PgPool;
+use crate::database::{get_client, query::{Column, Query}};
+use log::warn;
+
+
+/// What a request is counted against
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyKind {
+    Ip,
+    User,   // Session user, falls back to the IP before authentication
+}
+
+
+/// `limit` requests per `period` for each key, bursts up to `limit` (GCRA)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Policy {
+    pub limit: u32,
+    pub period: Duration,
+    pub key: KeyKind,
+}
+
+
+/// Limits of every scope, `None` means unlimited
+#[derive(Clone, Default)]
+pub struct Policies {
+    pub default: Option<Policy>,        // Every request
+    pub session_create: Option<Policy>, // POST /auth/session
+    pub note_create: Option<Policy>,    // POST /sample_db/create-note
+}
+
+
+/// Where a limit is applied, each scope has its own buckets
+#[derive(Clone, Copy)]
+pub enum Scope {
+    Default,
+    SessionCreate,
+    NoteCreate,
+}
+
+
+/// Outcome of one request against a policy
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,        // Until the bucket is full again
+    pub retry_after: Duration,  // Zero when allowed
+}
+
+
+/// Storage of the GCRA state (theoretical arrival time per key)
+pub trait RateLimitBackend: Send + Sync {
+    fn acquire<'a>(&'a self, key: &'a str, policy: &'a Policy) -> BoxFuture<'a, Result<Decision, String>>;
+}
+
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+
+/// Buckets kept in this process, each instance counts on its own
+pub struct MemoryBackend {
+    buckets: Cache<String, i64>,
+}
+
+
+/// Buckets in an unlogged Postgres table (migrations/0002_rate_limits.sql), shared by every instance using the database
+pub struct PostgresBackend {
+    pool: PgPool,
+}
+
+
+/// Applies the policies through a backend, the policies can be replaced at runtime
+pub struct RateLimiter {
+    policies: RwLock<Policies>,
+    backend: Box<dyn RateLimitBackend>,
+    trust_forwarded: bool,
+}
+
+
+/// Entries expire once their bucket is full again, nothing is left to remember
+struct ExpireWhenFull;
+
+
+// Keys the memory backend tracks at most, the least used are evicted first
+const MEMORY_MAX_KEYS: u64 = 100_000;
+
+// The Postgres backend purges the full buckets this often, off the request path
+const POSTGRES_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// One round trip GCRA: advance the arrival time if the request fits, otherwise leave it
+// `allowed_tat` is NULL when rejected, `stored_tat` is the value before this statement
+const ACQUIRE: Query = Query {
+    name: "acquire_rate_limit",
+    sql: r#"
+        WITH clock AS (
+            SELECT (extract(epoch FROM clock_timestamp()) * 1000)::BIGINT AS now_ms
+        ), acquired AS (
+            INSERT INTO rate_limits AS r (key, tat_ms)
+            SELECT $1, now_ms + $2 FROM clock
+            ON CONFLICT (key) DO UPDATE
+                SET tat_ms = GREATEST(r.tat_ms, EXCLUDED.tat_ms - $2) + $2
+                WHERE GREATEST(r.tat_ms, EXCLUDED.tat_ms - $2) + $2 <= EXCLUDED.tat_ms - $2 + $3
+            RETURNING tat_ms
+        )
+        SELECT
+            (SELECT now_ms FROM clock) AS now_ms,
+            (SELECT tat_ms FROM acquired) AS allowed_tat,
+            (SELECT tat_ms FROM rate_limits WHERE key = $1) AS stored_tat
+    "#,
+    columns: &[Column::new::<i64>("now_ms"), Column::new::<Option<i64>>("allowed_tat"), Column::new::<Option<i64>>("stored_tat")],
+    timeout: None,
+};
+
+const PURGE: Query = Query {
+    name: "purge_rate_limits",
+    sql: r#"
+        DELETE FROM rate_limits WHERE tat_ms < (extract(epoch FROM clock_timestamp()) * 1000)::BIGINT
+    "#,
+    columns: &[],
+    timeout: None,
+};
+
+/// Queries of the Postgres backend, checked against the schema at startup when it is used
+pub const QUERIES: &[&Query] = &[&ACQUIRE, &PURGE];
+
+
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+
+fn millis(ms: i64) -> Duration {
+    Duration::from_millis(ms.max(0) as u64)
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Policy {
+    /// Time one request "costs", the bucket refills one request per interval
+    fn emission_ms(&self) -> i64 {
+        (self.period.as_millis() as i64 / self.limit as i64).max(1)
+    }
+
+    fn period_ms(&self) -> i64 {
+        self.period.as_millis() as i64
+    }
+
+    /// Parse a setting value: `off`, or `<limit>/<seconds>:<ip|user>` like `10/60:ip`
+    pub fn parse_setting(value: &str) -> Result<Option<Policy>, String> {
+        match value.trim() {
+            "" | "off" => Ok(None),
+            value => value.parse().map(Some),
+        }
+    }
+
+    /// Value of the `RateLimit-Policy` header
+    pub fn header(&self) -> String {
+        format!("{};w={}", self.limit, self.period.as_secs())
+    }
+}
+
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a rate limit, expected <limit>/<seconds>:<ip|user> or off", s);
+
+        let (rate, key) = s.split_once(':').ok_or_else(invalid)?;
+        let (limit, period) = rate.split_once('/').ok_or_else(invalid)?;
+        let limit: u32 = limit.trim().parse().map_err(|_| invalid())?;
+        let period: u64 = period.trim().parse().map_err(|_| invalid())?;
+        let key = match key.trim() {
+            "ip" => KeyKind::Ip,
+            "user" => KeyKind::User,
+            _ => return Err(invalid()),
+        };
+
+        if limit == 0 || period == 0 {
+            return Err(format!("'{}': the limit and the period must be at least 1", s));
+        }
+        Ok(Policy { limit, period: Duration::from_secs(period), key })
+    }
+}
+
+
+impl fmt::Display for KeyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyKind::Ip => write!(f, "ip"),
+            KeyKind::User => write!(f, "user"),
+        }
+    }
+}
+
+
+impl Policies {
+    fn get(&self, scope: Scope) -> Option<&Policy> {
+        match scope {
+            Scope::Default => self.default.as_ref(),
+            Scope::SessionCreate => self.session_create.as_ref(),
+            Scope::NoteCreate => self.note_create.as_ref(),
+        }
+    }
+}
+
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Default => "default",
+            Scope::SessionCreate => "session_create",
+            Scope::NoteCreate => "note_create",
+        }
+    }
+}
+
+
+impl Decision {
+    /// Build the decision from the arrival time after the request (allowed) or before it (rejected)
+    fn new(policy: &Policy, now: i64, tat: i64, allowed: bool) -> Self {
+        let (emission, period) = (policy.emission_ms(), policy.period_ms());
+        let used = (tat - now).max(0);
+
+        Decision {
+            allowed,
+            limit: policy.limit,
+            remaining: ((period - used).max(0) / emission) as u32,
+            reset: millis(used),
+            retry_after: if allowed { Duration::ZERO } else { millis(tat + emission - now - period) },
+        }
+    }
+
+    /// Fail open: a backend error must not take the API down
+    fn unlimited(policy: &Policy) -> Self {
+        Decision { allowed: true, limit: policy.limit, remaining: policy.limit, reset: Duration::ZERO, retry_after: Duration::ZERO }
+    }
+}
+
+
+impl Expiry<String, i64> for ExpireWhenFull {
+    fn expire_after_create(&self, _key: &String, tat: &i64, _created_at: Instant) -> Option<Duration> {
+        Some(millis(tat - now_ms()))
+    }
+
+    fn expire_after_update(&self, _key: &String, tat: &i64, _updated_at: Instant, _current: Option<Duration>) -> Option<Duration> {
+        Some(millis(tat - now_ms()))
+    }
+}
+
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let buckets = Cache::builder()
+            .max_capacity(MEMORY_MAX_KEYS)
+            .expire_after(ExpireWhenFull)
+            .build();
+        MemoryBackend { buckets }
+    }
+}
+
+
+impl RateLimitBackend for MemoryBackend {
+    fn acquire<'a>(&'a self, key: &'a str, policy: &'a Policy) -> BoxFuture<'a, Result<Decision, String>> {
+        Box::pin(async move {
+            let now = now_ms();
+            let (emission, period) = (policy.emission_ms(), policy.period_ms());
+
+            // Calls on the same key are serialized by the cache
+            let result = self.buckets
+                .entry(key.to_string())
+                .and_compute_with(|entry| {
+                    let tat = entry.map(|e| e.into_value()).unwrap_or(now).max(now) + emission;
+                    std::future::ready(if tat - now <= period { Op::Put(tat) } else { Op::Nop })
+                })
+                .await;
+
+            Ok(match result {
+                CompResult::Inserted(entry) | CompResult::ReplacedWith(entry) => Decision::new(policy, now, entry.into_value(), true),
+                CompResult::Unchanged(entry) => Decision::new(policy, now, entry.into_value(), false),
+                CompResult::StillNone(_) | CompResult::Removed(_) => Decision::unlimited(policy),
+            })
+        })
+    }
+}
+
+
+impl PostgresBackend {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresBackend { pool }
+    }
+
+    /// Delete the full buckets in the background, a failed purge is retried at the next tick
+    pub fn spawn_purge(pool: PgPool) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(POSTGRES_PURGE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let purged = match get_client(&pool).await {
+                    Ok(client) => client.execute(PURGE.sql, &[]).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = purged {
+                    warn!("Could not purge the rate limit buckets: {}", e);
+                }
+            }
+        });
+    }
+}
+
+
+impl RateLimitBackend for PostgresBackend {
+    fn acquire<'a>(&'a self, key: &'a str, policy: &'a Policy) -> BoxFuture<'a, Result<Decision, String>> {
+        Box::pin(async move {
+            let client = get_client(&self.pool).await.map_err(|e| e.to_string())?;
+            let row = client
+                .query_one(ACQUIRE.sql, &[&key, &policy.emission_ms(), &policy.period_ms()])
+                .await
+                .map_err(|e| e.to_string())?;
+            let now: i64 = row.get("now_ms");
+
+            Ok(match row.get::<_, Option<i64>>("allowed_tat") {
+                Some(tat) => Decision::new(policy, now, tat, true),
+                None => Decision::new(policy, now, row.get::<_, Option<i64>>("stored_tat").unwrap_or(now), false),
+            })
+        })
+    }
+}
+
+
+impl RateLimiter {
+    pub fn new(policies: Policies, backend: Box<dyn RateLimitBackend>, trust_forwarded: bool) -> Self {
+        RateLimiter { policies: RwLock::new(policies), backend, trust_forwarded }
+    }
+
+    /// Replace the policies (config reload), the buckets are kept
+    pub fn set_policies(&self, policies: Policies) {
+        *self.policies.write().unwrap() = policies;
+    }
+
+    pub fn policy(&self, scope: Scope) -> Option<Policy> {
+        self.policies.read().unwrap().get(scope).cloned()
+    }
+
+    /// True if the client address may be read from `X-Forwarded-For` / `Forwarded` (behind a trusted proxy)
+    pub fn trust_forwarded(&self) -> bool {
+        self.trust_forwarded
+    }
+
+    /// Count one request of `client` against the policy of `scope`
+    pub async fn check(&self, scope: Scope, policy: &Policy, client: &str) -> Decision {
+        let key = format!("{}:{}:{}", scope.name(), policy.key, client);
+
+        match self.backend.acquire(&key, policy).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("Rate limit backend failed, letting the request through: {}", e);
+                Decision::unlimited(policy)
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    config::{CliArgs, RawConfig, SETTINGS},
+    models::initial::AppSettings,
+    middleware::cors::AllowedOrigins,
+    rate_limit::RateLimiter,
+    state::CertResolver,
+    utils::CacheTtl,
+    logging,
+};
+use std::{fs, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+use actix_web::web;
+use log::{info, warn};
+
+
+/// Re-reads the layered config and applies the settings that can change at runtime,
+/// the server (and its open connections) keeps running
+pub struct Reloader {
+    cli: CliArgs,
+    startup: RawConfig,
+    cache_ttl: CacheTtl,
+    cert_resolver: Option<Arc<CertResolver>>,
+    rate_limiter: web::Data<RateLimiter>,
+    cors_origins: web::Data<AllowedOrigins>,
+    watched: Mutex<Vec<PathBuf>>,
+}
+
+
+
+/// Files whose change triggers a reload: the config file and the TLS certificate and key
+fn files_to_watch(raw: &RawConfig, settings: &AppSettings) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = raw.config_file().map(PathBuf::from).into_iter().collect();
+    if let Some(tls) = &settings.server_settings.tls {
+        files.push(tls.cert_path.clone());
+        files.push(tls.key_path.clone());
+    }
+    files
+}
+
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+
+#[cfg(unix)]
+async fn hangup_signals() -> Option<tokio::signal::unix::Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())
+        .inspect_err(|e| warn!("Can not listen for SIGHUP, only file changes trigger a reload: {}", e))
+        .ok()
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Reloader {
+    pub fn new(
+        cli: CliArgs,
+        startup: RawConfig,
+        settings: &AppSettings,
+        cache_ttl: CacheTtl,
+        cert_resolver: Option<Arc<CertResolver>>,
+        rate_limiter: web::Data<RateLimiter>,
+        cors_origins: web::Data<AllowedOrigins>,
+    ) -> Self {
+        let watched = Mutex::new(files_to_watch(&startup, settings));
+        Reloader { cli, startup, cache_ttl, cert_resolver, rate_limiter, cors_origins, watched }
+    }
+
+    /// Load the config again and apply it, an invalid config is reported and ignored
+    pub fn reload(&self) {
+        let raw = RawConfig::load(&self.cli);
+        let settings = match AppSettings::from_config(&raw) {
+            Ok(settings) => settings,
+            Err(report) => {
+                warn!("Config reload rejected, keeping the current config. {}", report);
+                return;
+            }
+        };
+
+        // Apply what can change at runtime
+        logging::reconfigure(&settings.log_filter, settings.log_format);
+        self.cache_ttl.set(settings.cache_settings.expiration_time);
+        self.rate_limiter.set_policies(settings.rate_limit_settings.policies.clone());
+        self.cors_origins.set(settings.cors_settings.origins.clone());
+        if let (Some(resolver), Some(tls)) = (&self.cert_resolver, &settings.server_settings.tls)
+            && let Err(e) = resolver.reload(tls)
+        {
+            warn!("TLS certificate reload failed, keeping the current one: {}", e);
+        }
+
+        // Report what only a restart can apply
+        let mut restart: Vec<&str> = SETTINGS
+            .iter()
+            .filter(|s| !s.reloadable && self.startup.get(s.env) != raw.get(s.env))
+            .map(|s| s.key)
+            .collect();
+        if self.cert_resolver.is_some() != settings.server_settings.tls.is_some() {
+            restart.push("server.tls (enabling or disabling TLS)");
+        }
+        if !restart.is_empty() {
+            warn!("These changed settings need a restart to apply: {}", restart.join(", "));
+        }
+
+        *self.watched.lock().unwrap() = files_to_watch(&raw, &settings);
+        info!("Configuration reloaded");
+    }
+
+    /// Reload on SIGHUP, and when a watched file changes (checked every `interval`, 0 disables the check)
+    pub fn spawn(self, interval: Duration) {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = hangup_signals().await;
+            let mut ticker = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+            let mut last_seen = modified_times(&self.watched.lock().unwrap());
+
+            loop {
+                let by_signal = tokio::select! {
+                    _ = async {
+                        #[cfg(unix)]
+                        if let Some(hangup) = hangup.as_mut() {
+                            hangup.recv().await;
+                            return;
+                        }
+                        std::future::pending::<()>().await
+                    } => true,
+                    _ = async {
+                        match ticker.as_mut() {
+                            Some(ticker) => { ticker.tick().await; }
+                            None => std::future::pending::<()>().await,
+                        }
+                    } => false,
+                };
+
+                if by_signal {
+                    info!("SIGHUP received, reloading the configuration");
+                } else {
+                    let now = modified_times(&self.watched.lock().unwrap());
+                    if now == last_seen {
+                        continue;
+                    }
+                    info!("Config or TLS files changed, reloading the configuration");
+                }
+
+                self.reload();
+                last_seen = modified_times(&self.watched.lock().unwrap());
+            }
+        });
+    }
+}
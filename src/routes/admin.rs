@@ -0,0 +1,129 @@
+use crate::database::jobs::{cancel_job, fetch_job, list_jobs, retry_job};
+use crate::database::{replicas::ReplicaSet, DbError};
+use actix_web::{get, post, web, HttpResponse};
+use deadpool_postgres::PoolError;
+use tokio_postgres::error::SqlState;
+use crate::models::{
+    jobs::{JobFilter, JobResponse},
+    errors::{AppError, ErrorResp},
+};
+use utoipa::OpenApi;
+use uuid::Uuid;
+
+type ApiResp = Result<HttpResponse, AppError>;
+
+
+#[derive(OpenApi)]
+#[openapi(paths(list_jobs_handler, get_job_handler, retry_job_handler, cancel_job_handler))]
+pub struct AdminApi;
+
+
+
+/// ID of the path, a malformed one can not match any job
+fn job_id(path: &str) -> Result<Uuid, AppError> {
+    path.parse().map_err(|_| AppError::NotFound(format!("job {}", path)))
+}
+
+
+/// Why a job was not retried or cancelled: it does not exist, or it is in the wrong state for `action`
+async fn unchanged(db: &ReplicaSet, id: Uuid, action: &str) -> AppError {
+    match fetch_job(db.primary(), id).await {
+        Ok(Some(job)) => AppError::Conflict(format!("job {} is {}, it can not be {}", id, job.status, action)),
+        Ok(None) => AppError::NotFound(format!("job {}", id)),
+        Err(e) => e.into(),
+    }
+}
+
+
+#[utoipa::path(
+    tag = "admin",
+    params(JobFilter),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The newest jobs matching the filter", body = Vec<JobResponse>),
+        (status = 400, description = "Invalid filter", body = String),
+        (status = 401, description = "Missing or invalid admin token", body = String),
+    ),
+)]
+#[get("/jobs")]
+pub async fn list_jobs_handler(filter: web::Query<JobFilter>, db: web::Data<ReplicaSet>) -> ApiResp {
+    // From the primary, a replica may lag behind the workers
+    let rows = list_jobs(db.primary(), &filter).await?;
+
+    Ok(HttpResponse::Ok().json(JobResponse::from_rows(rows)))
+}
+
+
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "ID of the job")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The job", body = JobResponse),
+        (status = 401, description = "Missing or invalid admin token", body = String),
+        (status = 404, description = "No job with this ID", body = ErrorResp),
+    ),
+)]
+#[get("/jobs/{id}")]
+pub async fn get_job_handler(path: web::Path<String>, db: web::Data<ReplicaSet>) -> ApiResp {
+    let id = job_id(&path)?;
+
+    match fetch_job(db.primary(), id).await? {
+        Some(row) => Ok(HttpResponse::Ok().json(JobResponse::from(row))),
+        None => Err(AppError::NotFound(format!("job {}", id))),
+    }
+}
+
+
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "ID of the dead or cancelled job to run again")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The job, queued again with all its attempts", body = JobResponse),
+        (status = 401, description = "Missing or invalid admin token", body = String),
+        (status = 404, description = "No job with this ID", body = ErrorResp),
+        (status = 409, description = "The job is not dead or cancelled, or a job with the same dedupe key is waiting", body = ErrorResp),
+    ),
+)]
+#[post("/jobs/{id}/retry")]
+pub async fn retry_job_handler(path: web::Path<String>, db: web::Data<ReplicaSet>) -> ApiResp {
+    let id = job_id(&path)?;
+
+    match retry_job(db.primary(), id).await {
+        Ok(Some(row)) => {
+            log::info!("Job {} ({}) queued again by an admin", id, row.kind);
+            Ok(HttpResponse::Ok().json(JobResponse::from(row)))
+        }
+        Ok(None) => Err(unchanged(&db, id, "retried").await),
+        Err(DbError::Pool(PoolError::Backend(e))) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            Err(AppError::Conflict(format!("job {} has a dedupe key, and a job with the same key is waiting", id)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "ID of the queued job to cancel")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The cancelled job", body = JobResponse),
+        (status = 401, description = "Missing or invalid admin token", body = String),
+        (status = 404, description = "No job with this ID", body = ErrorResp),
+        (status = 409, description = "The job is not queued (a running job can not be cancelled)", body = ErrorResp),
+    ),
+)]
+#[post("/jobs/{id}/cancel")]
+pub async fn cancel_job_handler(path: web::Path<String>, db: web::Data<ReplicaSet>) -> ApiResp {
+    let id = job_id(&path)?;
+
+    match cancel_job(db.primary(), id).await? {
+        Some(row) => {
+            log::info!("Job {} ({}) cancelled by an admin", id, row.kind);
+            Ok(HttpResponse::Ok().json(JobResponse::from(row)))
+        }
+        None => Err(unchanged(&db, id, "cancelled").await),
+    }
+}
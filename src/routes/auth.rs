@@ -1,10 +1,34 @@
 use actix_web::{cookie::Cookie, delete, get, post, web, HttpResponse, Responder, HttpMessage, HttpRequest};
 use crate::utils::{AppCache, make_key, cache_data};
-use crate::models::user::SessionUser;
+use crate::invalidation::{publish, Eviction};
+use crate::database::replicas::ReplicaSet;
+use crate::models::{errors::ErrorResp, user::SessionUser};
+use actix_web::middleware::from_fn;
+use crate::middleware;
+use crate::metrics;
+use utoipa::OpenApi;
 
 
+#[derive(OpenApi)]
+#[openapi(paths(create_session_handler, get_session_handler, delete_session_handler))]
+pub struct AuthApi;
 
-#[post("/session")]
+
+
+#[utoipa::path(
+    tag = "auth",
+    request_body(content = String, content_type = "text/plain", description = "User name for the session"),
+    responses(
+        (
+            status = 200,
+            description = "Session created, the `Session-ID` cookie is set",
+            body = String,
+            headers(("X-CSRF-Token" = String, description = "Token to send back in the `X-CSRF-Token` header")),
+        ),
+        (status = 429, description = "Too many sessions created from this address", body = ErrorResp),
+    ),
+)]
+#[post("/session", wrap = "from_fn(middleware::rate_limit::limit_session_create)")]
 pub async fn create_session_handler(
     user_name: String,         // The request body (For now accept anything)
     state: web::Data<AppCache>, // The state containing the Cache
@@ -14,6 +38,7 @@ pub async fn create_session_handler(
 
     cache_data(&session.session_id, &session, &state).await;
     log::info!("Created new session: {}", session);
+    metrics::get().sessions_created.inc();
 
     HttpResponse::Ok()
         .insert_header(("Cache-Control", "no-cache"))
@@ -28,6 +53,14 @@ pub async fn create_session_handler(
 }
 
 
+#[utoipa::path(
+    tag = "auth",
+    security(("session_cookie" = [], "csrf_token" = [])),
+    responses(
+        (status = 200, description = "Greets the session's user", body = String),
+        (status = 401, description = "Missing or invalid session", body = String),
+    ),
+)]
 #[get("/session")]
 pub async fn get_session_handler(request: HttpRequest) -> impl Responder {
     // Get SessionUser from request extensions
@@ -38,14 +71,28 @@ pub async fn get_session_handler(request: HttpRequest) -> impl Responder {
 }
 
 
+#[utoipa::path(
+    tag = "auth",
+    security(("session_cookie" = [], "csrf_token" = [])),
+    responses(
+        (status = 200, description = "Session deleted and the cookie cleared", body = String),
+        (status = 401, description = "Missing or invalid session", body = String),
+    ),
+)]
 #[delete("/session")]
-pub async fn delete_session_handler(request: HttpRequest, state: web::Data<AppCache>) -> impl Responder {
-    // Get SessionUser from request extensions
-    let ext = request.extensions();
-    let session_user = ext.get::<SessionUser>().unwrap();
+pub async fn delete_session_handler(request: HttpRequest, state: web::Data<AppCache>, db: web::Data<ReplicaSet>) -> impl Responder {
+    // Get SessionUser from request extensions (drop the borrow before any await)
+    let session_id = {
+        let ext = request.extensions();
+        let session_user = ext.get::<SessionUser>().unwrap();
+        session_user.session_id.clone()
+    };
+    state.remove(&make_key(session_id.clone())).await;
 
-    let key = make_key(session_user.session_id.clone());
-    state.remove(&key).await;
+    // The other instances drop their copy too, else the session stays usable there until it expires
+    if let Err(e) = publish(db.primary(), &Eviction::Key(session_id)).await {
+        log::warn!("Could not publish the deletion of the session to the other instances: {}", e);
+    }
 
     let mut cookie = Cookie::build("Session-ID", "")
         .path("/")
@@ -0,0 +1,182 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use crate::models::{errors::ErrorResp, user::SessionUser};
+use super::{admin::AdminApi, auth::AuthApi, health::HealthApi, sample_db::NotesApi};
+use utoipa::{Modify, OpenApi};
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse};
+use crate::middleware::security_headers::CspNonce;
+
+
+// Swagger UI page, the assets are loaded from the CDN so nothing is bundled in the binary
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8" />
+    <title>Rust API - Swagger UI</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" crossorigin />
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+    <script nonce="{nonce}">
+        window.onload = () => {
+            window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui", withCredentials: true });
+        };
+    </script>
+</body>
+</html>
+"##;
+
+
+/// The OpenAPI document of the whole API, each route module contributes its own paths
+#[derive(OpenApi)]
+#[openapi(
+    nest(
+        (path = "/v1/health", api = HealthApi),
+        (path = "/v1/sample_db", api = NotesApi),
+        (path = "/v1/auth", api = AuthApi),
+        (path = "/v1/admin", api = AdminApi),
+    ),
+    components(schemas(ErrorResp, SessionUser)),
+    modifiers(&SessionSecurity),
+)]
+pub struct ApiDoc;
+
+
+/// Adds the cookie + CSRF header security schemes used by `auth_check`, and the bearer token of `admin_check`
+struct SessionSecurity;
+
+
+// ------- Implementations ------- //
+
+
+impl Modify for SessionSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session_cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("Session-ID"))),
+        );
+        components.add_security_scheme(
+            "csrf_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-CSRF-Token"))),
+        );
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+
+#[get("/openapi.json")]
+pub async fn openapi_json_handler() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+
+#[get("/docs")]
+pub async fn swagger_ui_handler(req: HttpRequest) -> HttpResponse {
+    // The inline script only runs with the nonce of the page's Content-Security-Policy
+    let nonce = req.extensions().get::<CspNonce>().map(|nonce| nonce.0.clone()).unwrap_or_default();
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI_HTML.replace("{nonce}", &nonce))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+    use crate::models::initial::ApiSettings;
+    use super::ApiDoc;
+    use utoipa::OpenApi;
+
+    // Every handler of a version as (method, path under the version, handler), the spec documents them under /v1
+    const ROUTES: [(&str, &str, &str); 18] = [
+        ("get", "/health", "health_report"),
+        ("get", "/health/live", "liveness_probe"),
+        ("get", "/health/ready", "readiness_probe"),
+        ("get", "/health/startup", "startup_probe"),
+        ("get", "/health/api", "api_health_check"),
+        ("get", "/health/pgsql", "db_health_check"),
+        ("get", "/health/cache", "cache_health_check"),
+        ("post", "/health/channel", "channel_health_check"),
+        ("post", "/sample_db/create-note", "create_note_handler"),
+        ("get", "/sample_db/notes", "list_notes_handler"),
+        ("patch", "/sample_db/notes/{id}", "update_note_handler"),
+        ("post", "/auth/session", "create_session_handler"),
+        ("get", "/auth/session", "get_session_handler"),
+        ("delete", "/auth/session", "delete_session_handler"),
+        ("get", "/admin/jobs", "list_jobs_handler"),
+        ("get", "/admin/jobs/{id}", "get_job_handler"),
+        ("post", "/admin/jobs/{id}/retry", "retry_job_handler"),
+        ("post", "/admin/jobs/{id}/cancel", "cancel_job_handler"),
+    ];
+
+    // Served outside the versions, not documented in the spec
+    const UNDOCUMENTED: [(&str, &str); 3] = [
+        ("/openapi.json", "openapi_json_handler"),
+        ("/docs", "swagger_ui_handler"),
+        ("/metrics", "metrics_handler"),
+    ];
+
+    // A path with a value for each parameter (`/jobs/{id}` -> `/jobs/1`)
+    fn sample(path: &str) -> String {
+        path.split('/').map(|segment| if segment.starts_with('{') { "1" } else { segment }).collect::<Vec<_>>().join("/")
+    }
+
+    // The spec documents exactly the handlers of `ROUTES`
+    #[actix_web::test]
+    async fn spec_matches_route_list() {
+        let spec = ApiDoc::openapi();
+        let mut documented = BTreeSet::new();
+        for (path, item) in spec.paths.paths.iter() {
+            let operations = [("get", &item.get), ("post", &item.post), ("put", &item.put), ("patch", &item.patch), ("delete", &item.delete)];
+            for (method, operation) in operations {
+                if let Some(operation) = operation {
+                    let id = operation.operation_id.clone().expect("operations are named after their handler");
+                    documented.insert((method.to_string(), path.clone(), id));
+                }
+            }
+        }
+
+        let listed: BTreeSet<_> = ROUTES.iter()
+            .map(|(method, path, handler)| (method.to_string(), format!("/v1{path}"), handler.to_string()))
+            .collect();
+        let not_listed: Vec<_> = documented.difference(&listed).collect();
+        assert!(not_listed.is_empty(), "in the spec but not in ROUTES: {not_listed:?}");
+        let not_documented: Vec<_> = listed.difference(&documented).collect();
+        assert!(not_documented.is_empty(), "in ROUTES but not in the spec: {not_documented:?}");
+    }
+
+    // Every handler of `ROUTES` is routed under each version and the legacy unversioned paths
+    #[actix_web::test]
+    async fn route_list_matches_routes() {
+        // The default service keeps a request, its resource map holds every route without going through middleware
+        let seen = Rc::new(RefCell::new(None));
+        let keep = seen.clone();
+        let app = test::init_service(App::new().configure(crate::routes::configure).default_service(web::to(move |req: HttpRequest| {
+            keep.replace(Some(req));
+            async { HttpResponse::Ok().finish() }
+        }))).await;
+        test::call_service(&app, test::TestRequest::get().uri("/unrouted").to_request()).await;
+        let req = seen.take().expect("the default service saw the request");
+        let rmap = req.resource_map();
+
+        let prefixes = ApiSettings::SUPPORTED_VERSIONS.iter().map(|version| format!("/v{version}")).chain([String::new()]);
+        for prefix in prefixes {
+            for (_, route, handler) in ROUTES {
+                let path = format!("{prefix}{route}");
+                assert_eq!(rmap.match_pattern(&sample(&path)).as_deref(), Some(path.as_str()), "{handler} is not routed at {path}");
+                // Handlers sharing a path are separate resources, the map only names the first one
+                let names: Vec<_> = ROUTES.iter().filter(|(_, other, _)| *other == route).map(|(_, _, name)| *name).collect();
+                let name = rmap.match_name(&sample(&path));
+                assert!(name.is_some_and(|name| names.contains(&name)), "{path} is routed to {name:?}, not {names:?}");
+            }
+        }
+        for (path, handler) in UNDOCUMENTED {
+            assert_eq!(rmap.match_name(path), Some(handler), "{handler} is not routed at {path}");
+        }
+    }
+}
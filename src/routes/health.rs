@@ -1,21 +1,124 @@
-use crate::{
-    database::health_check as db_health_check_pgsql,
-    models::errors::AppError
-};
+use crate::handlers::health::{ComponentHealth, HealthChecker, HealthReport, Status};
+use crate::models::errors::{AppError, ErrorResp};
+use crate::utils::{make_key, AppCache};
+use crate::jobs::JobQueue;
 use actix_web::{get, post, web, HttpResponse};
-use crate::utils::{AppCache, make_key};
+use crate::database::{health_check as db_health_check_pgsql, replicas::ReplicaSet};
 use deadpool_postgres::Pool as PgPool;
-use std::sync::mpsc::Sender;
+use crate::shutdown::Lifecycle;
+use serde_json::json;
+use utoipa::{openapi::{self, Deprecated}, Modify, OpenApi};
 
 
-// Health check endpoint
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        health_report, liveness_probe, readiness_probe, startup_probe,
+        api_health_check, db_health_check, cache_health_check, channel_health_check,
+    ),
+    components(schemas(HealthReport, ComponentHealth, Status)),
+    modifiers(&DeprecatedProbes),
+)]
+pub struct HealthApi;
+
+
+/// Flags the old probes as deprecated in the spec
+struct DeprecatedProbes;
+
+
+// Operations of the probes replaced by `/live`, `/ready`, `/startup` and the report, removed in the next release
+const DEPRECATED_PROBES: [&str; 4] = ["api_health_check", "db_health_check", "cache_health_check", "channel_health_check"];
+
+
+// Aggregated health of every component, 503 when a critical one is down
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "All components are up, or only non-critical ones are failing", body = HealthReport),
+    (status = 503, description = "A critical component is down", body = HealthReport),
+))]
+#[get("")]
+async fn health_report(
+    checker: web::Data<HealthChecker>,
+    db: web::Data<ReplicaSet>,
+    cache: web::Data<AppCache>,
+    jobs: web::Data<JobQueue>,
+) -> HttpResponse {
+    let report = checker.report(&db, &cache, &jobs).await;
+
+    match report.status {
+        Status::Down => HttpResponse::ServiceUnavailable().json(report),
+        _ => HttpResponse::Ok().json(report),
+    }
+}
+
+
+// Liveness: the process answers requests, no dependency is checked (a restart would not fix them)
+#[utoipa::path(tag = "health", responses((status = 200, description = "Server is alive")))]
+#[get("/live")]
+async fn liveness_probe() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": Status::Up }))
+}
+
+
+// Readiness: route traffic here only while not shutting down and every critical component is up
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "Server is ready for traffic", body = HealthReport),
+    (status = 503, description = "Server is shutting down or a critical component is down", body = HealthReport),
+))]
+#[get("/ready")]
+async fn readiness_probe(
+    lifecycle: web::Data<Lifecycle>,
+    checker: web::Data<HealthChecker>,
+    db: web::Data<ReplicaSet>,
+    cache: web::Data<AppCache>,
+    jobs: web::Data<JobQueue>,
+) -> HttpResponse {
+    if lifecycle.is_draining() {
+        return HttpResponse::ServiceUnavailable().json(json!({ "status": Status::Down, "reason": "shutting down" }));
+    }
+
+    let report = checker.report(&db, &cache, &jobs).await;
+    match report.status {
+        Status::Down => HttpResponse::ServiceUnavailable().json(report),
+        _ => HttpResponse::Ok().json(report),
+    }
+}
+
+
+// Startup: fails until the primary answers and the background tasks are running, then liveness and readiness take over
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "Initialization finished"),
+    (status = 503, description = "Still starting up, `pending` lists the components not ready yet"),
+))]
+#[get("/startup")]
+async fn startup_probe(lifecycle: web::Data<Lifecycle>) -> HttpResponse {
+    if lifecycle.is_initialized() {
+        HttpResponse::Ok().json(json!({ "status": Status::Up }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({ "status": Status::Down, "reason": "starting up", "pending": lifecycle.pending() }))
+    }
+}
+
+
+// Deprecated: use `/health/ready`
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "Server is running", body = String),
+    (status = 503, description = "Server is shutting down", body = String),
+))]
 #[get("/api")]
-async fn api_health_check() -> HttpResponse {
+async fn api_health_check(lifecycle: web::Data<Lifecycle>) -> HttpResponse {
+    if lifecycle.is_draining() {
+        return HttpResponse::ServiceUnavailable().body("Server is shutting down!");
+    }
     HttpResponse::Ok().body("Server is running!")
 }
 
 
-// Database health check
+// Deprecated: use the `postgres` component of `/health`
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "Database is running", body = String),
+    (status = 424, description = "Could not get a connection from the pool", body = ErrorResp),
+    (status = 417, description = "The health query failed", body = ErrorResp),
+))]
 #[get("/pgsql")]
 async fn db_health_check(state: web::Data<PgPool>) -> Result<HttpResponse, AppError> {
     db_health_check_pgsql(&state).await?;
@@ -23,28 +126,43 @@ async fn db_health_check(state: web::Data<PgPool>) -> Result<HttpResponse, AppEr
 }
 
 
-// Cache health check
+// Deprecated: use the `cache` component of `/health`
+#[utoipa::path(tag = "health", responses(
+    (status = 200, description = "Cache is running", body = String),
+    (status = 412, description = "Cache did not return the value it stored", body = String),
+))]
 #[get("/cache")]
 async fn cache_health_check(cache: web::Data<AppCache>) -> HttpResponse {
     const CACHE_KEY: &str = "health_check";
     const CACHE_VALUE: &str = "Cache is running!";
-    let key = make_key(CACHE_KEY);
-
-    cache.insert(key, CACHE_VALUE.to_string()).await;
 
-    if let Some(cached_value) = cache.get(&make_key(CACHE_KEY)).await {
-        if cached_value == CACHE_VALUE {
-            return HttpResponse::Ok().body(cached_value);
-        }
+    cache.insert(make_key(CACHE_KEY), CACHE_VALUE.to_string()).await;
+    match cache.get(&make_key(CACHE_KEY)).await {
+        Some(value) if value == CACHE_VALUE => HttpResponse::Ok().body(value),
+        _ => HttpResponse::PreconditionFailed().body("Cache health check failed!"),
     }
-    HttpResponse::PreconditionFailed().body("Cache health check failed!")
 }
 
 
-// Channel Health check
+// Deprecated: the channel it tested was replaced by the job queue, use the `jobs` component of `/health`
+#[utoipa::path(tag = "health", responses((status = 200, description = "Always, nothing is sent anymore", body = String)))]
 #[post("/channel")]
-async fn channel_health_check(state: web::Data<Sender<u8>>) -> HttpResponse {
-    // Send a 7 int to the channel
-    let _ = state.send(7);
+async fn channel_health_check() -> HttpResponse {
     HttpResponse::Ok().body("Channel health check initiated!")
 }
+
+
+// ------- Implementations ------- //
+
+
+impl Modify for DeprecatedProbes {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            for operation in [&mut item.get, &mut item.post].into_iter().flatten() {
+                if operation.operation_id.as_deref().is_some_and(|id| DEPRECATED_PROBES.contains(&id)) {
+                    operation.deprecated = Some(Deprecated::True);
+                }
+            }
+        }
+    }
+}
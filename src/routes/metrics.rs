@@ -0,0 +1,14 @@
+use actix_web::{get, web, HttpResponse};
+use crate::database::replicas::ReplicaSet;
+use crate::utils::AppCache;
+use crate::metrics;
+
+
+
+// Prometheus scrape endpoint
+#[get("/metrics")]
+pub async fn metrics_handler(db: web::Data<ReplicaSet>, cache: web::Data<AppCache>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(metrics::get().render(&db, &cache))
+}
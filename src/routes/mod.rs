@@ -1,3 +1,81 @@
+use actix_web::{middleware::from_fn, web::{scope as actix_scope, ServiceConfig}};
+use crate::middleware;
+
+pub mod admin;
 pub mod auth;
+pub mod docs;
 pub mod health;
+pub mod metrics;
 pub mod sample_db;
+
+
+/// Scopes mounted under every API version (`/v1/health`, `/v2/health`, ...)
+pub const VERSIONED_SCOPES: [&str; 4] = ["/health", "/sample_db", "/auth", "/admin"];
+
+
+/// Register all the routes of the API (shared by the server and the tests)
+pub fn configure(cfg: &mut ServiceConfig) {
+    cfg
+        .service(docs::openapi_json_handler)
+        .service(docs::swagger_ui_handler)
+        .service(metrics::metrics_handler)
+        .service(
+            actix_scope("/v1")
+            .wrap(from_fn(middleware::versioning::deprecation_headers))
+            .configure(api)
+        )
+        .service(
+            actix_scope("/v2")
+            .wrap(from_fn(middleware::versioning::deprecation_headers))
+            .configure(api)
+        )
+        .service(
+            // Unversioned paths kept for older clients, deprecated in favour of the default version
+            actix_scope("")
+            .wrap(from_fn(middleware::versioning::deprecation_headers))
+            .configure(api)
+        );
+}
+
+
+/// Handlers shared by every API version, give a version its own function once it diverges
+fn api(cfg: &mut ServiceConfig) {
+    cfg
+        .service(
+            actix_scope("/health")
+            .service(health::health_report)
+            .service(health::liveness_probe)
+            .service(health::readiness_probe)
+            .service(health::startup_probe)
+            // Deprecated probes, removed in the next release
+            .service(health::api_health_check)
+            .service(health::db_health_check)
+            .service(health::cache_health_check)
+            .service(health::channel_health_check)
+        )
+        .service(
+            actix_scope("/sample_db")
+            .wrap(from_fn(middleware::auth::auth_check))
+            .service(sample_db::create_note_handler)
+            .service(sample_db::list_notes_handler)
+            .service(sample_db::update_note_handler)
+        )
+        .service(
+            actix_scope("/auth")
+            .service(auth::create_session_handler)
+            .service(
+                actix_scope("")
+                .wrap(from_fn(middleware::auth::auth_check))
+                .service(auth::delete_session_handler)
+                .service(auth::get_session_handler)
+            )
+        )
+        .service(
+            actix_scope("/admin")
+            .wrap(from_fn(middleware::auth::admin_check))
+            .service(admin::list_jobs_handler)
+            .service(admin::get_job_handler)
+            .service(admin::retry_job_handler)
+            .service(admin::cancel_job_handler)
+        );
+}
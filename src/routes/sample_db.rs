@@ -1,39 +1,123 @@
-use actix_web::{get, post, web, HttpRequest, HttpResponse, HttpMessage};
-use crate::database::notes::{add_new_notes, fetch_all_notes};
-use deadpool_postgres::Pool as PgPool;
+use crate::database::notes::{fetch_all_notes, update_single_note};
+use actix_web::{get, patch, post, web, HttpRequest, HttpResponse, HttpMessage};
+use crate::database::replicas::ReplicaSet;
+use crate::jobs::{job::Job, JobQueue};
+use actix_web::middleware::from_fn;
+use crate::middleware;
 use crate::models::{
+    notes::{CreateNote, NoteResponse, UpdateNote},
+    errors::{AppError, ErrorResp},
     user::SessionUser,
-    errors::AppError,
-    notes::Notes,
 };
+use utoipa::OpenApi;
 
 type ApiResp = Result<HttpResponse, AppError>;
 
 
+#[derive(OpenApi)]
+#[openapi(paths(create_note_handler, list_notes_handler, update_note_handler))]
+pub struct NotesApi;
 
-#[post("/create-note")]
-pub async fn create_note_handler(request: HttpRequest, body: web::Json<Notes>, pg_pool: web::Data<PgPool>) -> ApiResp {
-    // Get SessionUser from request extensions
-    let ext = request.extensions();
-    let session_user = ext.get::<SessionUser>().unwrap();
 
-    log::trace!("{} is creating a new note.", session_user);
 
-    add_new_notes(&pg_pool, vec![body.into_inner()]).await?;
+#[utoipa::path(
+    tag = "notes",
+    request_body = CreateNote,
+    security(("session_cookie" = [], "csrf_token" = [])),
+    responses(
+        (status = 200, description = "Note queued for insertion", body = String),
+        (status = 401, description = "Missing or invalid session", body = String),
+        (status = 429, description = "Too many notes created by this user", body = ErrorResp),
+        (status = 424, description = "Could not get a connection from the pool", body = ErrorResp),
+        (status = 503, description = "Postgres is down (circuit breaker open) or too many jobs are queued, see Retry-After", body = ErrorResp),
+    ),
+)]
+#[post("/create-note", wrap = "from_fn(middleware::rate_limit::limit_note_create)")]
+pub async fn create_note_handler(
+    request: HttpRequest,
+    body: web::Json<CreateNote>,
+    db: web::Data<ReplicaSet>,
+    jobs: web::Data<JobQueue>,
+) -> ApiResp {
+    let session_id = {
+        // Get SessionUser from request extensions (drop the borrow before any await)
+        let ext = request.extensions();
+        let session_user = ext.get::<SessionUser>().unwrap();
+
+        log::trace!("{} is creating a new note.", session_user);
+        session_user.session_id.clone()
+    };
+
+    // Inserted in the background by a job worker, the job is stored first so it survives a restart
+    let job_id = jobs.enqueue(Job::InsertNotes(vec![body.into_inner()])).await?;
+    log::debug!("Note insert queued as job {}", job_id);
+    db.mark_write(&session_id).await;
 
     Ok(HttpResponse::Ok().json("Note created successfully!"))
 }
 
 
+#[utoipa::path(
+    tag = "notes",
+    security(("session_cookie" = [], "csrf_token" = [])),
+    responses(
+        (status = 200, description = "All the notes", body = Vec<NoteResponse>),
+        (status = 401, description = "Missing or invalid session", body = String),
+        (status = 424, description = "Could not get a connection from the pool", body = ErrorResp),
+        (status = 503, description = "Postgres is down (circuit breaker open), see Retry-After", body = ErrorResp),
+    ),
+)]
 #[get("/notes")]
-pub async fn list_notes_handler(request: HttpRequest, pg_pool: web::Data<PgPool>) -> ApiResp {
-    // Get SessionUser from request extensions
-    let ext = request.extensions();
-    let session_user = ext.get::<SessionUser>().unwrap();
+pub async fn list_notes_handler(request: HttpRequest, db: web::Data<ReplicaSet>) -> ApiResp {
+    let session_id = {
+        // Get SessionUser from request extensions (drop the borrow before any await)
+        let ext = request.extensions();
+        let session_user = ext.get::<SessionUser>().unwrap();
+
+        log::trace!("User '{}' is listing notes.", session_user.user_name);
+        session_user.session_id.clone()
+    };
+
+    // Read-only, served by a replica unless this session just wrote
+    let rows = fetch_all_notes(db.reader(Some(&session_id))).await?;
+
+    Ok(HttpResponse::Ok().json(NoteResponse::from_rows(rows)))
+}
+
+
+#[utoipa::path(
+    tag = "notes",
+    params(("id" = i32, Path, description = "ID of the note to update")),
+    request_body = UpdateNote,
+    security(("session_cookie" = [], "csrf_token" = [])),
+    responses(
+        (status = 200, description = "The updated note", body = NoteResponse),
+        (status = 401, description = "Missing or invalid session", body = String),
+        (status = 404, description = "No note with this ID", body = ErrorResp),
+        (status = 422, description = "Nothing to update", body = ErrorResp),
+    ),
+)]
+#[patch("/notes/{id}")]
+pub async fn update_note_handler(request: HttpRequest, path: web::Path<i32>, body: web::Json<UpdateNote>, db: web::Data<ReplicaSet>) -> ApiResp {
+    let id = path.into_inner();
+    let session_id = {
+        // Get SessionUser from request extensions (drop the borrow before any await)
+        let ext = request.extensions();
+        let session_user = ext.get::<SessionUser>().unwrap();
 
-    log::trace!("User '{}' is listing notes.", session_user.user_name);
+        log::trace!("User '{}' is updating note {}.", session_user.user_name, id);
+        session_user.session_id.clone()
+    };
 
-    let notes = fetch_all_notes(&pg_pool).await?;
+    let note = body.into_inner();
+    if note.is_empty() {
+        return Err(AppError::Unprocessable("nothing to update".to_string()));
+    }
 
-    Ok(HttpResponse::Ok().json(notes))
+    let row = update_single_note(db.primary(), id, &note).await?;
+    db.mark_write(&session_id).await;
+    match row {
+        Some(row) => Ok(HttpResponse::Ok().json(NoteResponse::from(row))),
+        None => Err(AppError::NotFound(format!("note {}", id))),
+    }
 }
@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use actix_web::{dev::ServerHandle, web};
+use crate::{database::{get_client, replicas::ReplicaSet}, invalidation, jobs::JobQueue, state::AppState, telemetry};
+use deadpool_postgres::Pool as PgPool;
+use log::{info, warn};
+
+
+/// Shared shutdown state: readiness and in-flight requests
+#[derive(Default)]
+pub struct Lifecycle {
+    initialized: AtomicBool,
+    pending: Mutex<Vec<&'static str>>,  // Components the startup still waits for
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    served: AtomicU64,
+    started: OnceLock<Instant>,
+}
+
+
+/// Counts a request as in flight until dropped
+pub struct RequestGuard(web::Data<Lifecycle>);
+
+
+/// How the shutdown went, logged at the end
+struct Summary {
+    elapsed: Duration,
+    served: u64,
+    jobs_processed: u64,
+    jobs_abandoned: usize,
+}
+
+
+
+// The startup checks are repeated this often until everything is ready
+const STARTUP_POLL: Duration = Duration::from_millis(250);
+
+
+
+/// Components not ready yet: the primary can not be reached, a replica was never checked, the cache invalidation
+/// does not listen yet or the job workers never claimed
+async fn startup_pending(replicas: &ReplicaSet, jobs: &JobQueue) -> Vec<&'static str> {
+    let mut pending = Vec::new();
+    if get_client(replicas.primary()).await.is_err() {
+        pending.push("postgres");
+    }
+    if !replicas.is_checked() {
+        pending.push("postgres_replicas");
+    }
+    if !invalidation::is_listening() {
+        pending.push("cache_invalidation");
+    }
+    if !jobs.is_started() {
+        pending.push("jobs");
+    }
+    pending
+}
+
+
+/// Called once the listeners are bound: the startup probe passes when every component is ready, after that
+/// liveness and readiness take over
+pub fn spawn_startup(state: &AppState) {
+    let (lifecycle, replicas, jobs) = (state.lifecycle.clone(), state.replicas.clone(), state.jobs.clone());
+    tokio::spawn(async move {
+        let started = Instant::now();
+        loop {
+            let pending = startup_pending(&replicas, &jobs).await;
+            if pending.is_empty() {
+                lifecycle.initialized.store(true, Ordering::SeqCst);
+                info!("Startup finished in {:.2?}", started.elapsed());
+                return;
+            }
+            *lifecycle.pending.lock().unwrap() = pending;
+            tokio::time::sleep(STARTUP_POLL).await;
+        }
+    });
+}
+
+
+/// Wait for SIGINT (Ctrl+C) or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Can not listen for SIGTERM, only Ctrl+C stops the server: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+
+/// On SIGINT / SIGTERM: fail readiness, wait `delay` so load balancers notice, stop accepting
+/// connections, drain in-flight requests (up to `timeout`), then stop the server
+pub fn handle_signals(server: ServerHandle, lifecycle: web::Data<Lifecycle>, delay: Duration, timeout: Duration) {
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received, readiness is now failing");
+        let _ = lifecycle.started.set(Instant::now());
+        lifecycle.draining.store(true, Ordering::SeqCst);
+        tokio::time::sleep(delay).await;
+
+        // Stop accepting new connections, the open ones are still served
+        server.pause().await;
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        while lifecycle.in_flight.load(Ordering::SeqCst) > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        server.stop(true).await;
+    });
+}
+
+
+/// Once the server stopped: finish the running jobs, close the PG pool and log a summary
+pub async fn finish(state: AppState, timeout: Duration) {
+    let AppState { pg_pool, replicas, jobs, lifecycle, .. } = state;
+
+    // The jobs run on this runtime, so they can still use the pool. The queued ones wait in the table.
+    let jobs_abandoned = jobs.shutdown(timeout).await;
+
+    pg_pool.close();
+    replicas.close();
+    telemetry::shutdown();
+
+    let summary = Summary {
+        elapsed: lifecycle.started.get().map(Instant::elapsed).unwrap_or_default(),
+        served: lifecycle.served.load(Ordering::SeqCst),
+        jobs_processed: jobs.processed(),
+        jobs_abandoned,
+    };
+    info!("{}", summary);
+}
+
+
+/// `worker` subcommand: run the jobs until SIGINT / SIGTERM, then finish the running ones (up to `timeout`),
+/// close the PG pool and log a summary
+pub async fn run_worker(pg_pool: PgPool, jobs: JobQueue, timeout: Duration) {
+    shutdown_signal().await;
+    info!("Shutdown signal received, no more jobs are claimed");
+    let started = Instant::now();
+
+    let jobs_abandoned = jobs.shutdown(timeout).await;
+    pg_pool.close();
+    telemetry::shutdown();
+
+    info!(
+        "Worker stopped in {:.2?}: {} jobs processed, {} jobs abandoned, Postgres pool closed",
+        started.elapsed(), jobs.processed(), jobs_abandoned,
+    );
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Lifecycle {
+    /// True once `spawn_startup` found every component ready, it never goes back
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::SeqCst)
+    }
+
+    /// Components the startup is waiting for
+    pub fn pending(&self) -> Vec<&'static str> {
+        self.pending.lock().unwrap().clone()
+    }
+
+    /// True once shutdown started, readiness must fail
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+
+impl RequestGuard {
+    pub fn new(lifecycle: web::Data<Lifecycle>) -> Self {
+        lifecycle.in_flight.fetch_add(1, Ordering::SeqCst);
+        RequestGuard(lifecycle)
+    }
+}
+
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.served.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Shutdown complete in {:.2?}: {} requests served, {} jobs processed, {} jobs abandoned, Postgres pool closed",
+            self.elapsed,
+            self.served,
+            self.jobs_processed,
+            self.jobs_abandoned,
+        )
+    }
+}
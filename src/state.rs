@@ -1,11 +1,21 @@
-use crate::models::initial::{AppSettings, MokaSettings, PgSettings};
+use crate::models::initial::{ApiSettings, AppSettings, MokaSettings, PgSettings, RateLimitBackendKind, TlsSettings};
+use crate::middleware::cors::AllowedOrigins;
+use crate::rate_limit::{MemoryBackend, PostgresBackend, RateLimitBackend, RateLimiter};
 use deadpool_postgres::{Manager, RecyclingMethod, Pool as PgPool};
-use crate::utils::{process_channel, AppCache};
 use deadpool::{managed::Timeouts, Runtime};
 use actix_web::web::Data as webData;
-use tokio_postgres::{Config, NoTls};
-use std::sync::mpsc::Sender;
+use crate::database::replicas::{Replica, ReplicaSet};
+use crate::{database, handlers::health::HealthChecker, invalidation, logging, metrics, shutdown::Lifecycle, telemetry};
+use tokio_postgres::Config;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use crate::utils::{AppCache, CacheTtl};
+use crate::jobs::{job::JobContext, JobQueue};
+use std::sync::{Arc, RwLock};
+use rustls::sign::CertifiedKey;
+use moka::notification::RemovalCause;
 use std::time::Duration;
+use std::io;
 use log::{info, warn};
 
 
@@ -17,20 +27,9 @@ async fn warm_pool(pool: &PgPool, pg: &PgSettings) {
         return;
     }
 
+    // The connections are opened concurrently
     let warm_n = pg.max_pool_size.min(pg.warm_pool_size);
-    let mut ok = 0;
-
-    for _ in 0..warm_n {
-        match pool.get().await {
-            Ok(client) => {
-                let _ = client.simple_query("SELECT 1").await;
-                ok += 1;
-            }
-            Err(_) => {
-                warn!("Pool warm-up: failed to get a connection");
-            }
-        }
-    }
+    let ok = database::pool::warm_up(pool, warm_n).await;
 
     // Log the warm-up results
     if ok == 0 {
@@ -41,26 +40,38 @@ async fn warm_pool(pool: &PgPool, pg: &PgSettings) {
 }
 
 
-fn build_pg_config(settings: &PgSettings) -> Config {
+fn build_pg_config(settings: &PgSettings, url: &str) -> Config {
     // Initialize the Postgres configuration
-    let mut cfg: Config = settings.url.parse::<Config>().expect("invalid POSTGRES_DB_URL");
+    let mut cfg: Config = url.parse::<Config>().expect("invalid Postgres connection string");
     cfg.application_name("rust-api");
     cfg.connect_timeout(Duration::from_secs(settings.conn_timeout));
+    cfg.ssl_mode(settings.tls.mode.negotiation());
+
+    // The server cancels runaway statements even if the cancel request of the app never arrives
+    if !settings.statement_timeout.is_zero() {
+        let options = format!("{} -c statement_timeout={}", cfg.get_options().unwrap_or_default(), settings.statement_timeout.as_millis());
+        cfg.options(options.trim_start());
+    }
 
     cfg
 }
 
 
-fn init_pg_pool(pg_settings: &PgSettings) -> PgPool {
+/// Pool for the primary or a replica (`url`), sized and secured the same way, `name` labels its metrics
+fn init_pg_pool(pg_settings: &PgSettings, url: &str, name: &str) -> PgPool {
     // Get the Postgres base configuration
-    let cfg: Config = build_pg_config(pg_settings);
+    let cfg: Config = build_pg_config(pg_settings, url);
+    let tls = database::tls::make_tls_connect(&pg_settings.tls).expect("Postgres TLS settings are checked by AppSettings::from_config");
+    database::timeout::set_cancel_tls(tls.clone());
     let mgr = Manager::from_config(
         cfg,
-        NoTls,
+        tls,
         deadpool_postgres::ManagerConfig {
+            // The configured check runs in the recycle hook, which counts its failures
             recycling_method: RecyclingMethod::Fast,
         },
     );
+    let recycle_timeout = Duration::from_secs(pg_settings.recycle_timeout);
 
     let pool = PgPool::builder(mgr)
         .max_size(pg_settings.max_pool_size)
@@ -71,50 +82,239 @@ fn init_pg_pool(pg_settings: &PgSettings) -> PgPool {
             // how long to spend creating a new connection (if pool can grow)
             create: Some(Duration::from_secs(pg_settings.new_connection_timeout)),
             // how long to spend recycling/validating a connection
-            recycle: Some(Duration::from_secs(pg_settings.recycle_timeout)),
+            recycle: Some(recycle_timeout),
         })
+        .pre_recycle(database::pool::recycle_hook(name, &pg_settings.recycling, recycle_timeout))
         .build()
         .expect("failed to build pg pool");
 
-    info!("Postgres pool initialized (max_pool_size={})", pg_settings.max_pool_size);
+    // Fail fast while Postgres is down instead of piling up checkouts
+    database::breaker::register(&pool, name, pg_settings.breaker.threshold, pg_settings.breaker.cooldown);
+
+    // Keep idle connections ready in the background if enabled
+    database::pool::spawn_min_idle(pool.clone(), name.to_string(), pg_settings.min_idle);
+
+    info!(
+        "Postgres pool '{}' initialized (max_pool_size={}, min_idle={}, recycling={}, sslmode={})",
+        name, pg_settings.max_pool_size, pg_settings.min_idle, pg_settings.recycling, pg_settings.tls.mode,
+    );
     pool
 }
 
 
-fn init_cache(cache_settings: &MokaSettings) -> AppCache {
-    // Build the AppCache
+/// Primary pool plus one pool per read replica, named after its host
+fn init_replica_set(pg_settings: &PgSettings, primary: PgPool) -> ReplicaSet {
+    let replicas = pg_settings.replicas.urls.iter().enumerate().map(|(i, (url, weight))| {
+        let cfg = build_pg_config(pg_settings, url);
+        let host = match cfg.get_hosts().first() {
+            Some(tokio_postgres::config::Host::Tcp(host)) => format!("{}:{}", host, cfg.get_ports().first().unwrap_or(&5432)),
+            _ => format!("replica-{}", i),
+        };
+        let pool = init_pg_pool(pg_settings, url, &host);
+        Replica::new(host, pool, *weight)
+    }).collect();
+
+    let settings = &pg_settings.replicas;
+    ReplicaSet::new(primary, replicas, settings.max_lag, settings.read_your_writes)
+}
+
+
+fn init_cache(cache_settings: &MokaSettings) -> (AppCache, CacheTtl) {
+    // Build the AppCache, the TTL is read on every write so it can be reloaded
+    let ttl = CacheTtl::new(cache_settings.expiration_time);
     let cache: AppCache = AppCache::builder()
         .max_capacity(cache_settings.cache_size)
-        .time_to_live(cache_settings.expiration_time)
+        .expire_after(ttl.clone())
+        .support_invalidation_closures()   // Namespaces evicted by the invalidation channel
+        .eviction_listener(|_key, _value, cause| {
+            let cause = match cause {
+                RemovalCause::Expired => "expired",
+                RemovalCause::Size => "size",
+                RemovalCause::Explicit | RemovalCause::Replaced => return,
+            };
+            metrics::get().cache_evictions.with_label_values(&[cause]).inc();
+        })
         .build();
 
     info!("In-memory cache initialized (max_capacity={})", cache_settings.cache_size);
-    cache
+    (cache, ttl)
+}
+
+
+/// Everything the server shares with its workers
+pub struct AppState {
+    pub pg_pool: webData<PgPool>,
+    pub replicas: webData<ReplicaSet>,
+    pub cache: webData<AppCache>,
+    pub jobs: webData<JobQueue>,
+    pub api_settings: webData<ApiSettings>,
+    pub lifecycle: webData<Lifecycle>,
+    pub cache_ttl: CacheTtl,
+    pub health: webData<HealthChecker>,
+    pub rate_limiter: webData<RateLimiter>,
+    pub cors_origins: webData<AllowedOrigins>,
+}
+
+
+/// Serves the current certificate, swapped on reload without touching open connections
+#[derive(Debug)]
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+
+impl CertResolver {
+    fn load_key(tls: &TlsSettings) -> io::Result<CertifiedKey> {
+        let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid TLS certificate {}: {}", tls.cert_path.display(), e)))?;
+        let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid TLS private key {}: {}", tls.key_path.display(), e)))?;
+
+        CertifiedKey::from_der(certs, key, &rustls::crypto::ring::default_provider())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("TLS certificate and key do not match: {}", e)))
+    }
+
+    /// Load the certificate again, the old one stays in place if the new files are invalid
+    pub fn reload(&self, tls: &TlsSettings) -> io::Result<()> {
+        let key = Self::load_key(tls)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        info!("TLS certificate reloaded from {}", tls.cert_path.display());
+        Ok(())
+    }
+}
+
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
 }
 
 
-pub async fn initialize() -> (webData<PgPool>, webData<AppCache>, webData<Sender<u8>>) {
-    // Preparing to start the server by collecting environment variables
-    let app_settings: AppSettings = AppSettings::from_env();
+/// Build the rustls server config from the PEM files, HTTP/2 is offered through ALPN when enabled
+/// The returned resolver is used to swap the certificate at runtime
+pub fn load_tls_config(tls: &TlsSettings, http2: bool) -> io::Result<(rustls::ServerConfig, Arc<CertResolver>)> {
+    let resolver = Arc::new(CertResolver { current: RwLock::new(Arc::new(CertResolver::load_key(tls)?)) });
 
+    let mut config = rustls::ServerConfig::builder_with_provider(rustls::crypto::ring::default_provider().into())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    info!("TLS enabled with certificate {}", tls.cert_path.display());
+    Ok((config, resolver))
+}
+
+
+/// Logging, tracing and the pool of the primary, shared by the server and the `worker` subcommand
+async fn init_primary(app_settings: &AppSettings, what: &str) -> PgPool {
     if app_settings.enable_logging {
-        let _ = env_logger::try_init(); // Initialize the logger to log all the logs
-        info!("Starting the server by initializing the application state");
+        logging::init(&app_settings.log_filter, app_settings.log_format); // Initialize the logger, the filter and format can be reloaded later
+        info!("Starting the {} by initializing the application state", what);
     }
 
-    // Initialize the Postgres client
-    let postgres_state = init_pg_pool(&app_settings.pg_settings);
+    // Export spans to the OTLP collector if one is configured
+    telemetry::init(&app_settings.tracing_settings);
+
+    // Initialize the Postgres client, idempotent reads are retried after transient errors
+    database::retry::set_policy(app_settings.pg_settings.retry);
+    let postgres_state = init_pg_pool(&app_settings.pg_settings, &app_settings.pg_settings.url, "primary");
 
     // Warm up the connection pool if enabled
     warm_pool(&postgres_state, &app_settings.pg_settings).await;
 
+    // Refuse to start when a query does not match the schema (e.g. a missing migration), reported like an invalid config
+    let mut queries = [database::notes::QUERIES, database::jobs::QUERIES].concat();
+    if app_settings.rate_limit_settings.backend == RateLimitBackendKind::Postgres {
+        queries.extend_from_slice(crate::rate_limit::QUERIES);
+    }
+    if !app_settings.pg_settings.replicas.urls.is_empty() {
+        queries.extend_from_slice(database::replicas::QUERIES);
+    }
+    if app_settings.pg_settings.check_queries
+        && let Err(e) = database::query::check_queries(&postgres_state, &queries).await
+    {
+        eprintln!("Queries do not match the database schema (are the migrations in migrations/ applied?):\n{}", e);
+        std::process::exit(2);
+    }
+
+    postgres_state
+}
+
+
+pub async fn initialize(app_settings: &AppSettings) -> AppState {
+    let postgres_state = init_primary(app_settings, "server").await;
+
+    // Read replicas, reads only go to the ones the monitor found healthy
+    let replicas = webData::new(init_replica_set(&app_settings.pg_settings, postgres_state.clone()));
+    ReplicaSet::spawn_monitor(replicas.clone(), app_settings.pg_settings.replicas.check_interval);
+
     // Initialize the in-memory cache (Moka)
-    let in_mem_cache = init_cache(&app_settings.cache_settings);
+    let (in_mem_cache, cache_ttl) = init_cache(&app_settings.cache_settings);
+
+    // Evict what the other instances wrote or deleted, through a dedicated connection to the primary
+    if let Some(channel) = &app_settings.cache_settings.invalidation_channel {
+        let pg = &app_settings.pg_settings;
+        let tls = database::tls::make_tls_connect(&pg.tls).expect("Postgres TLS settings are checked by AppSettings::from_config");
+        invalidation::spawn_listener(build_pg_config(pg, &pg.url), tls, channel.clone(), in_mem_cache.clone());
+    }
 
-    // Initialize the channel
-    let (tx, rx) = std::sync::mpsc::channel::<u8>();
-    process_channel(rx);
+    // Background jobs, run on this runtime so they can finish after the HTTP workers stopped
+    let job_settings = &app_settings.job_settings;
+    let jobs = if job_settings.run_workers {
+        JobQueue::start(job_settings, JobContext { db: postgres_state.clone() })
+    } else {
+        info!("Job workers are off in this process, `worker` processes run the jobs");
+        JobQueue::new(job_settings, postgres_state.clone())
+    };
+
+    // Component checks behind the health endpoints
+    let health = HealthChecker::new(app_settings.health_settings.check_timeout, app_settings.health_settings.cache_ttl);
+
+    // Rate limiter, the buckets are shared through Postgres when several instances run
+    let rate_limit = &app_settings.rate_limit_settings;
+    let backend: Box<dyn RateLimitBackend> = match rate_limit.backend {
+        RateLimitBackendKind::Memory => Box::new(MemoryBackend::new()),
+        RateLimitBackendKind::Postgres => {
+            PostgresBackend::spawn_purge(postgres_state.clone());
+            Box::new(PostgresBackend::new(postgres_state.clone()))
+        }
+    };
+    let rate_limiter = RateLimiter::new(rate_limit.policies.clone(), backend, rate_limit.trust_forwarded);
 
     // Wrap the state of the application and share it
-    (webData::new(postgres_state), webData::new(in_mem_cache), webData::new(tx))
+    AppState {
+        pg_pool: webData::new(postgres_state),
+        replicas,
+        cache: webData::new(in_mem_cache),
+        jobs: webData::new(jobs),
+        api_settings: webData::new(app_settings.api_settings.clone()),
+        lifecycle: webData::new(Lifecycle::default()),
+        cache_ttl,
+        health: webData::new(health),
+        rate_limiter: webData::new(rate_limiter),
+        cors_origins: webData::new(AllowedOrigins::new(app_settings.cors_settings.origins.clone())),
+    }
+}
+
+
+/// What the `worker` subcommand runs: the job workers on the primary pool, no HTTP server
+pub async fn initialize_worker(app_settings: &AppSettings) -> (PgPool, JobQueue) {
+    let postgres_state = init_primary(app_settings, "job worker").await;
+
+    // The jobs publish their cache evictions to the API processes, nothing to evict here
+    if let Some(channel) = &app_settings.cache_settings.invalidation_channel {
+        invalidation::set_channel(channel);
+    }
+
+    let jobs = JobQueue::start(&app_settings.job_settings, JobContext { db: postgres_state.clone() });
+    (postgres_state, jobs)
 }
@@ -0,0 +1,159 @@
+use opentelemetry::{global, propagation::{Extractor, Injector}, trace::{TraceContextExt, TracerProvider as _}, Context};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::{Sampler, SdkTracerProvider}, Resource};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt};
+use crate::models::initial::TracingSettings;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing::level_filters::LevelFilter;
+use std::sync::OnceLock;
+use log::{info, warn};
+
+
+// Kept to flush the buffered spans on shutdown
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+
+/// Reads W3C trace context from the request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+
+/// Writes W3C trace context into the response headers
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+
+
+/// Install the tracing subscriber exporting spans over OTLP/HTTP, does nothing when no endpoint is configured
+pub fn init(settings: &TracingSettings) {
+    let Some(endpoint) = &settings.otlp_endpoint else {
+        // Without any subscriber, spans would be written to the log as plain lines
+        let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default());
+        return;
+    };
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            warn!("Tracing disabled, the OTLP exporter could not be built: {}", e);
+            return;
+        }
+    };
+
+    // Follow the caller's sampling decision, sample new traces by ratio
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(settings.sample_ratio)));
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(sampler)
+        .with_resource(Resource::builder().with_service_name(settings.service_name.clone()).build())
+        .build();
+    let tracer = provider.tracer("rust-api");
+
+    // Only our own spans, the exporter's HTTP client must not trace itself
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .with(Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::INFO));
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        warn!("Tracing disabled, a tracing subscriber is already installed");
+        return;
+    }
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let _ = PROVIDER.set(provider);
+    info!("Tracing enabled, exporting spans to {} (sample ratio {})", endpoint, settings.sample_ratio);
+}
+
+
+/// Export the spans still buffered, called once the server stopped
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        warn!("Flushing the remaining spans failed: {}", e);
+    }
+}
+
+
+/// Trace context sent by the caller (`traceparent` / `tracestate`), empty if there is none
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+
+/// Add the `traceparent` of the given context to outgoing headers
+pub fn inject_context(context: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| propagator.inject_context(context, &mut HeaderInjector(headers)));
+}
+
+
+/// Trace id of the current span, None when tracing is off or nothing is traced
+pub fn current_trace_id() -> Option<String> {
+    PROVIDER.get()?;
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context.is_valid().then(|| span_context.trace_id().to_string())
+}
+
+
+/// Strip the literals from a SQL statement and collapse its whitespace, safe to attach to a span
+pub fn sanitize_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut previous = ' ';
+
+    while let Some(c) = chars.next() {
+        match c {
+            // String literal ('' is an escaped quote inside it)
+            '\'' => {
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                out.push('?');
+            }
+            // Number literal, but not a `$1` placeholder or part of an identifier
+            c if c.is_ascii_digit() && !(previous.is_alphanumeric() || previous == '_' || previous == '$') => {
+                while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+                out.push('?');
+            }
+            c if c.is_whitespace() => {
+                if !out.is_empty() && !out.ends_with(' ') {
+                    out.push(' ');
+                }
+            }
+            c => out.push(c),
+        }
+        previous = out.chars().last().unwrap_or(' ');
+    }
+
+    out.trim_end().to_string()
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use std::sync::mpsc::Receiver;
-use moka::future::Cache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use moka::{future::Cache, Expiry};
 use std::sync::Arc;
+use tracing::Instrument;
+use crate::metrics;
 
 
 // Cache key and value types
@@ -9,6 +12,11 @@ type Value = String;
 pub type AppCache = Cache<Key, Value>;
 
 
+/// Time to live of the cache entries, can be changed at runtime (applies to entries written after the change)
+#[derive(Clone)]
+pub struct CacheTtl(Arc<AtomicU64>);
+
+
 /// Create a cache key from a string-like value
 pub fn make_key<S>(s: S) -> Key
     where S: Into<Value>
@@ -17,6 +25,32 @@ pub fn make_key<S>(s: S) -> Key
 }
 
 
+impl CacheTtl {
+    pub fn new(ttl: Duration) -> Self {
+        CacheTtl(Arc::new(AtomicU64::new(ttl.as_secs())))
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_secs(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, ttl: Duration) {
+        self.0.store(ttl.as_secs(), Ordering::Relaxed);
+    }
+}
+
+
+impl Expiry<Key, Value> for CacheTtl {
+    fn expire_after_create(&self, _key: &Key, _value: &Value, _created_at: Instant) -> Option<Duration> {
+        Some(self.get())
+    }
+
+    fn expire_after_update(&self, _key: &Key, _value: &Value, _updated_at: Instant, _current: Option<Duration>) -> Option<Duration> {
+        Some(self.get())
+    }
+}
+
+
 /// Cache data into the cache
 pub async fn cache_data<T, S>(key: S, value: &T, cache_conn: &AppCache)
     where
@@ -30,24 +64,47 @@ pub async fn cache_data<T, S>(key: S, value: &T, cache_conn: &AppCache)
     let json = serde_json::to_string(value).unwrap();
 
     // Insert the JSON string into the cache
-    cache_conn.insert(make_key(key), json).await;
+    cache_conn.insert(make_key(key), json).instrument(tracing::info_span!("cache.insert")).await;
+}
+
+
+/// Look a key up in the cache, counting hits and misses for the metrics
+pub async fn get_cached(key: &Key, cache_conn: &AppCache) -> Option<Value> {
+    let span = tracing::info_span!("cache.get", cache.hit = tracing::field::Empty);
+    let value = cache_conn.get(key).instrument(span.clone()).await;
+    let result = if value.is_some() { "hit" } else { "miss" };
+    span.record("cache.hit", value.is_some());
+    metrics::get().cache_lookups.with_label_values(&[result]).inc();
+    value
 }
 
 
-/// Process the channel
-pub fn process_channel(rx: Receiver<u8>) {
-    std::thread::spawn(move || {
-        // Keep reading
-        loop {
-            match rx.recv() {
-                Ok(val) => {
-                    log::info!("Received: {}", val);
-                }
-                Err(_) => {
-                    log::warn!("Channel closed");
-                    break;
-                }
-            }
+/// Split a `<scope>=<value>` per scope override (`/health=*`), the scope is kept without its trailing `/`
+pub fn parse_scope_override(s: &str) -> Result<(String, &str), String> {
+    let (scope, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not a scope override, expected <scope>=<value>", s))?;
+    let scope = scope.trim().trim_end_matches('/');
+    if !scope.starts_with('/') {
+        return Err(format!("'{}' is not a scope, it must start with /", scope));
+    }
+    Ok((scope.to_string(), value.trim()))
+}
+
+
+/// Value of the longest scope containing the path, `/v1`, `/v2` ... prefixes are ignored so scopes match every version
+pub fn match_scope<'a, T>(scopes: &'a [(String, T)], path: &str) -> Option<&'a T> {
+    let path = match path.strip_prefix("/v") {
+        Some(rest) => {
+            let end = rest.find('/').unwrap_or(rest.len());
+            if end > 0 && rest[..end].bytes().all(|b| b.is_ascii_digit()) { &rest[end..] } else { path }
         }
-    });
+        None => path,
+    };
+
+    scopes
+        .iter()
+        .filter(|(scope, _)| path.strip_prefix(scope.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+        .max_by_key(|(scope, _)| scope.len())
+        .map(|(_, value)| value)
 }